@@ -0,0 +1,150 @@
+//! Broadcast latency and idle CPU of a real `svchat -s` process.
+//!
+//! Run with `cargo bench --bench latency`. Idle CPU is read from `/proc`, so
+//! that part is only reported on Linux.
+
+use std::{
+    convert::TryInto,
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+const ROUNDS: usize = 200;
+const IDLE_WINDOW: Duration = Duration::from_secs(3);
+/// Longest to wait for any event; a stalled server fails the bench instead of hanging it.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Sends far faster than the default rate limit allows, so the bench server raises it.
+const CONFIG: &str = "[server.rate_limit]\nmessages_per_sec = 1000000.0\nmessage_burst = 1000000.0\n";
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn spawn_server(port: u16, dir: &Path) -> Child {
+    fs::create_dir_all(dir).unwrap();
+    fs::write(dir.join("svchat.toml"), CONFIG).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_svchat"))
+        .args(["-s", "-p", &port.to_string()])
+        .arg("--config")
+        .arg(dir.join("svchat.toml"))
+        .arg("--history-dir")
+        .arg(dir.join("history"))
+        .arg("--user-db")
+        .arg(dir.join("users.json"))
+        .arg("--moderation-db")
+        .arg(dir.join("moderation.json"))
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start server");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "server did not come up");
+        sleep(Duration::from_millis(20));
+    }
+    child
+}
+
+fn send(stream: &mut TcpStream, value: &Value) {
+    let payload = serde_json::to_vec(value).unwrap();
+    stream.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+    stream.write_all(&payload).unwrap();
+}
+
+fn recv(stream: &mut TcpStream) -> Value {
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).expect("server stopped answering");
+    let mut payload = vec![0; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+fn connect(port: u16, username: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    send(&mut stream, &json!({
+        "type": "hello",
+        "version": 1,
+        "capabilities": [],
+        "username": username,
+        "room": "_default",
+    }));
+    assert_eq!(recv(&mut stream)["type"], "welcome");
+    stream
+}
+
+/// Receives until a chat message arrives, skipping presence and other events.
+fn recv_message(stream: &mut TcpStream) -> Value {
+    loop {
+        let event = recv(stream);
+        if event["type"] == "message" {
+            return event;
+        }
+    }
+}
+
+fn cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Fields after the parenthesised command name; utime and stime are 14 and 15
+    let fields: Vec<&str> = stat.rsplit(')').next()?.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+fn main() {
+    let port = free_port();
+    let dir = std::env::temp_dir().join(format!("svchat-bench-{}", port));
+    let mut server = spawn_server(port, &dir);
+
+    let mut sender = connect(port, "bench-sender");
+    let mut receiver = connect(port, "bench-receiver");
+
+    let mut samples = Vec::with_capacity(ROUNDS);
+    for i in 0..ROUNDS {
+        let started = Instant::now();
+        send(&mut sender, &json!({
+            "type": "message",
+            "room": "_default",
+            "content": i.to_string(),
+            "color": "White",
+        }));
+        let msg = recv_message(&mut receiver);
+        samples.push(started.elapsed());
+        assert_eq!(msg["content"], i.to_string());
+        recv_message(&mut sender);
+    }
+    samples.sort();
+
+    let at = |q: f64| samples[((samples.len() - 1) as f64 * q) as usize];
+    let mean = samples.iter().sum::<Duration>() / samples.len().try_into().unwrap();
+    println!("broadcast latency over {} messages:", ROUNDS);
+    println!("  min {:?}  median {:?}  mean {:?}  p99 {:?}  max {:?}", at(0.0), at(0.5), mean, at(0.99), at(1.0));
+
+    match cpu_ticks(server.id()) {
+        Some(before) => {
+            sleep(IDLE_WINDOW);
+            let after = cpu_ticks(server.id()).unwrap();
+            // /proc reports in USER_HZ, which is 100 on every mainstream Linux build
+            let busy = (after - before) as f64 / 100.0;
+            println!(
+                "idle cpu with 2 clients connected: {:.2}% over {:?}",
+                busy / IDLE_WINDOW.as_secs_f64() * 100.0,
+                IDLE_WINDOW
+            );
+        }
+        None => println!("idle cpu: not available on this platform"),
+    }
+
+    server.kill().ok();
+    server.wait().ok();
+    fs::remove_dir_all(&dir).ok();
+}
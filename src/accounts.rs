@@ -0,0 +1,167 @@
+use std::{collections::HashMap, fs, io::{self, Write}, path::PathBuf, sync::mpsc, thread};
+
+use argon2::{password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Argon2};
+use chrono::{DateTime, Utc};
+use mio::Token;
+use serde::{Serialize, Deserialize};
+
+use crate::server::{Control, Handle};
+
+pub const MIN_PASSWORD: usize = 8;
+
+#[derive(Clone)]
+pub struct AuthConfig {
+	pub user_db: PathBuf,
+	/// Whether clients must log in or register before joining any room.
+	pub required: bool,
+}
+
+impl Default for AuthConfig {
+	fn default() -> Self {
+		Self {
+			user_db: crate::config::data_dir().unwrap_or_default().join("users.json"),
+			required: false,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+	username: String,
+	/// Argon2id hash in PHC string format, salt included.
+	hash: String,
+	created: DateTime<Utc>,
+}
+
+/// Registered accounts, saved to the user database file on every change.
+pub struct Accounts {
+	config: AuthConfig,
+	/// Keyed by lowercased username, like the server's username registry.
+	accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+	/// Reads the user database, starting empty if it doesn't exist yet.
+	pub fn load(config: AuthConfig) -> io::Result<Accounts> {
+		let list: Vec<Account> = match fs::read(&config.user_db) {
+			Ok(content) => serde_json::from_slice(&content)?,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+			Err(err) => return Err(err),
+		};
+		let accounts = list.into_iter().map(|a| (a.username.to_lowercase(), a)).collect();
+		Ok(Accounts { config, accounts })
+	}
+
+	pub fn required(&self) -> bool {
+		self.config.required
+	}
+
+	pub fn set_required(&mut self, required: bool) {
+		self.config.required = required;
+	}
+
+	pub fn is_registered(&self, username: &str) -> bool {
+		self.accounts.contains_key(&username.to_lowercase())
+	}
+
+	/// The password hash of an account, for the worker to check a login against.
+	pub fn hash(&self, username: &str) -> Option<String> {
+		self.accounts.get(&username.to_lowercase()).map(|account| account.hash.clone())
+	}
+
+	/// Checks that `username` can be registered with `password`, before it gets hashed.
+	pub fn check_new(&self, username: &str, password: &str) -> Result<(), String> {
+		if self.is_registered(username) {
+			return Err(format!("Username {} is already registered", username));
+		}
+		if password.chars().count() < MIN_PASSWORD {
+			return Err(format!("Passwords must be at least {} characters long", MIN_PASSWORD));
+		}
+		Ok(())
+	}
+
+	/// Registers an account with a password the worker hashed.
+	pub fn register(&mut self, username: &str, hash: String) -> Result<(), String> {
+		if self.is_registered(username) {
+			return Err(format!("Username {} is already registered", username));
+		}
+		self.accounts.insert(username.to_lowercase(), Account {
+			username: username.to_string(),
+			hash,
+			created: Utc::now(),
+		});
+		if let Err(err) = self.save() {
+			log!(Error, "Failed to save user database: {}", err);
+			self.accounts.remove(&username.to_lowercase());
+			return Err(String::from("Failed to save the account, try again later"));
+		}
+		Ok(())
+	}
+
+	/// Writes the database to a temporary file and moves it over the old one,
+	/// so a crash never leaves it half written.
+	fn save(&self) -> io::Result<()> {
+		if let Some(dir) = self.config.user_db.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		let mut list: Vec<&Account> = self.accounts.values().collect();
+		list.sort_by(|a, b| a.username.cmp(&b.username));
+
+		let tmp = self.config.user_db.with_extension("tmp");
+		// The mode only applies to a new file, so one left over by a crash goes first
+		match fs::remove_file(&tmp) {
+			Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+			_ => (),
+		}
+		let mut options = fs::OpenOptions::new();
+		options.write(true).create_new(true);
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::OpenOptionsExt;
+			options.mode(0o600);
+		}
+		options.open(&tmp)?.write_all(&serde_json::to_vec_pretty(&list)?)?;
+		fs::rename(&tmp, &self.config.user_db)
+	}
+}
+
+/// Password work for the worker thread, so Argon2 never holds up the event loop.
+pub enum Job {
+	Verify { token: Token, hash: String, password: String },
+	Hash { token: Token, password: String },
+}
+
+pub enum Outcome {
+	Verified(bool),
+	Hashed(Result<String, String>),
+}
+
+/// Starts the worker thread; outcomes go back to the event loop through `handle`.
+pub fn spawn_worker(handle: Handle) -> mpsc::Sender<Job> {
+	let (jobs_tx, jobs) = mpsc::channel();
+	thread::spawn(move || {
+		for job in jobs {
+			let (token, outcome) = match job {
+				Job::Verify { token, hash, password } => (token, Outcome::Verified(verify(&hash, &password))),
+				Job::Hash { token, password } => (token, Outcome::Hashed(hash(&password))),
+			};
+			handle.send(Control::Password(token, outcome));
+		}
+	});
+	jobs_tx
+}
+
+fn verify(hash: &str, password: &str) -> bool {
+	match PasswordHash::new(hash) {
+		Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+		Err(_) => false,
+	}
+}
+
+fn hash(password: &str) -> Result<String, String> {
+	let salt = SaltString::generate(&mut OsRng);
+	Argon2::default()
+		.hash_password(password.as_bytes(), &salt)
+		.map(|hash| hash.to_string())
+		.map_err(|err| format!("Failed to hash password: {}", err))
+}
@@ -0,0 +1,143 @@
+use std::net::SocketAddr;
+
+use serde::{Serialize, Deserialize};
+
+use crate::moderation::{self, Role};
+
+/// Usage of the commands both the admin console and `svchat admin` take.
+pub const COMMANDS: &str = "\
+rooms                                List rooms and how many are in each
+users                                List everyone connected
+stats                                Show how busy the server is
+kick <user> [reason]                 Disconnect a user from the server
+ban <room> <user|ip> [for] [reason]  Ban from a room, e.g. ban lobby bob 1h spamming
+broadcast <text>                     Send a notice to everyone
+say <room> <text>                    Post a message to a room as the server
+role <room> <account> <role>         Make an account member, operator or owner of a room
+reload                               Read the config file again";
+
+/// What the server's operator can ask of the event loop, from the admin
+/// console or as a line of JSON on the control socket.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+	Rooms,
+	Users,
+	Stats,
+	/// Disconnects a user from the whole server.
+	Kick { username: String, reason: Option<String> },
+	/// Bans a user or address from a room; for good if `seconds` is missing.
+	Ban { room: String, target: String, seconds: Option<u64>, reason: Option<String> },
+	/// Sends a notice to everyone connected.
+	Broadcast { content: String },
+	/// Posts a message to a room as the server.
+	Say { room: String, content: String },
+	/// Gives a registered account a role in a room, e.g. the owner of the default room.
+	SetRole { room: String, username: String, role: Role },
+	/// Reads the config file again and applies what can change while running.
+	Reload,
+}
+
+impl Command {
+	/// Parses a command as typed, e.g. `kick bob spamming`.
+	pub fn parse(words: &[&str]) -> Option<Command> {
+		let command = match words {
+			["rooms"] => Command::Rooms,
+			["users"] => Command::Users,
+			["stats"] => Command::Stats,
+			["kick", username, reason @ ..] => Command::Kick { username: username.to_string(), reason: text(reason) },
+			["ban", room, target, args @ ..] => {
+				let (seconds, reason) = moderation::duration_and_text(args);
+				Command::Ban { room: room.to_string(), target: target.to_string(), seconds, reason }
+			}
+			["broadcast", content @ ..] if !content.is_empty() => Command::Broadcast { content: content.join(" ") },
+			["say", room, content @ ..] if !content.is_empty() => Command::Say { room: room.to_string(), content: content.join(" ") },
+			["role", room, username, role] => {
+				let role = match *role {
+					"member" => Role::Member,
+					"operator" => Role::Operator,
+					"owner" => Role::Owner,
+					_ => return None,
+				};
+				Command::SetRole { room: room.to_string(), username: username.to_string(), role }
+			}
+			["reload"] => Command::Reload,
+			_ => return None,
+		};
+		Some(command)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+	Rooms { rooms: Vec<RoomInfo> },
+	Users { users: Vec<UserInfo> },
+	Stats(Stats),
+	Done { message: String },
+	Error { reason: String },
+}
+
+impl Reply {
+	/// The reply as text for a person to read.
+	pub fn lines(&self) -> Vec<String> {
+		match self {
+			Reply::Rooms { rooms } => rooms.iter().map(|room| match &room.owner {
+				Some(owner) => format!("{}: {} online, owned by {}", room.name, room.members, owner),
+				None => format!("{}: {} online", room.name, room.members),
+			}).collect(),
+			Reply::Users { users } if users.is_empty() => vec![String::from("Nobody is connected")],
+			Reply::Users { users } => users.iter().map(|user| {
+				let account = match &user.account {
+					Some(account) => format!(" (account {})", account),
+					None => String::new(),
+				};
+				format!("{}{} from {} in {}", user.username, account, user.addr, user.rooms.join(", "))
+			}).collect(),
+			Reply::Stats(stats) => vec![
+				format!("Up for {}s", stats.uptime),
+				format!("{} connections, {} users in {} rooms", stats.connections, stats.users, stats.rooms),
+				format!("{} messages since starting", stats.messages),
+			],
+			Reply::Done { message } => vec![message.clone()],
+			Reply::Error { reason } => vec![reason.clone()],
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoomInfo {
+	pub name: String,
+	pub members: usize,
+	pub owner: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserInfo {
+	pub username: String,
+	pub addr: SocketAddr,
+	/// Lowercased account name, if the user logged in.
+	pub account: Option<String>,
+	pub rooms: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Stats {
+	/// Seconds since the server started.
+	pub uptime: u64,
+	/// Open connections, including clients still in the handshake.
+	pub connections: usize,
+	pub users: usize,
+	pub rooms: usize,
+	/// Messages posted to rooms since the server started.
+	pub messages: u64,
+}
+
+/// The rest of a command as one string, or `None` if there is none.
+fn text(words: &[&str]) -> Option<String> {
+	if words.is_empty() {
+		None
+	} else {
+		Some(words.join(" "))
+	}
+}
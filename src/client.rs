@@ -1,50 +1,352 @@
-use std::{error::Error, io::{self, ErrorKind, Read, Write}, mem::size_of_val, net::TcpStream, process::exit, sync::{Arc, Mutex, MutexGuard, mpsc}, thread::{self, sleep}, time::{Duration}};
-use chrono::{DateTime, Local, Utc};
-use crossterm::{self, event::{KeyCode, read, Event}};
+use std::{collections::HashMap, error::Error, io, net::TcpStream, process::exit, sync::{Arc, Mutex, MutexGuard, mpsc}, thread::{self, sleep}, time::{Duration}};
+use chrono::{DateTime, Local};
+use crossterm::{self, event::{KeyCode, KeyModifiers, poll, read, Event}, terminal::{disable_raw_mode, enable_raw_mode}};
 use tui::{
-    backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Style},
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
     text::{Span, Spans},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
     Terminal,
 };
 use unicode_width::UnicodeWidthStr;
 
-use crate::{structs::{Msg, ConnectionRequest, MsgType, MessageWrapper}, config::Config};
+use crate::{e2e::{self, Identity}, structs::{Msg, DirectMsg, ConnectionRequest, DEFAULT_ROOM}, moderation::{self, Role}, protocol::{self, ClientCommand, ModAction, ServerEvent, Throttle, MAX_CONTENT_SIZE, PROTOCOL_VERSION}, config::{self, Config}, frame::{FrameDecoder, FrameEncoder, FrameError, DEFAULT_MAX_FRAME_SIZE}, tls::{self, Stream}};
 
 pub const COLOR_INFO: Color = Color::LightBlue;
 pub const COLOR_ERR: Color = Color::LightRed;
+pub const HELP: &str = "Available commands: /help, /nick <nickname>, /local-color <color>, /remote-color <color>, /rooms, /create <room>, /join <room>, /open <room>, /leave [room], /who [room], /sidebar, /msg <user> <text>, /query [user], /search <text>, /register <password>, /verify <user>, /kick <user> [reason], /ban <user|ip> [duration] [reason], /unban <user|ip>, /mute <user> [duration] [reason], /unmute <user>, /op <user>, /deop <user>, /owner <user>";
 pub const COLORS: [&str; 16] = ["black", "red","green", "yellow", "blue", "magenta", "cyan", "gray", "darkgray", "lightred", "lightgreen", "lightyellow", "lightblue", "lightmagenta", "lightcyan", "white"];
 
 pub struct Client {
 	pub name: String,
+    /// Room that plain messages are sent to
+    pub room: String,
+    /// Every room the server has confirmed we are in
+    pub rooms: Vec<String>,
+    /// User whose query window is open; plain messages go to them instead of the room
+    pub query: Option<String>,
+    /// Last known members of each joined room
+    pub members: HashMap<String, Vec<String>>,
+    /// Members of rooms whose list is still arriving in pages
+    paged: HashMap<String, Vec<String>>,
+    pub show_members: bool,
     pub local_color: Color,
-    pub remote_color: Color
+    pub remote_color: Color,
+    /// Keypair direct messages are sealed with
+    pub identity: Identity,
+    /// Published keys of other users, by lowercased name
+    pub keys: HashMap<String, String>,
+    /// Server address the keys below were remembered for
+    server: String,
+    /// Keys seen on this server in earlier sessions, by lowercased name
+    known: HashMap<String, String>,
+    /// Direct messages and `/verify`s waiting for a user's key, by lowercased name
+    waiting: HashMap<String, Vec<Waiting>>
+}
+
+/// Something that can only be done once a user's key has arrived.
+enum Waiting {
+    Message(String),
+    Verify
 }
 
 impl Client {
-	fn new(username: String) -> Client{
-		return Client {
+	fn new(username: String, config: &Config, identity: Identity, server: String) -> Client{
+		Client {
 			name: username,
-            local_color: Color::White,
-            remote_color: Color::White
+            room: String::from(DEFAULT_ROOM),
+            rooms: Vec::new(),
+            query: None,
+            members: HashMap::new(),
+            paged: HashMap::new(),
+            show_members: true,
+            local_color: color_from_name(&config.env.local_color).unwrap_or(Color::White),
+            remote_color: color_from_name(&config.env.remote_color).unwrap_or(Color::White),
+            identity,
+            keys: HashMap::new(),
+            known: config::load_known_keys(&server),
+            server,
+            waiting: HashMap::new()
 		}
 	}
+
+    /// Records a joined room and makes it the current one.
+    fn joined(&mut self, room: &str) {
+        if !self.rooms.iter().any(|r| r == room) {
+            self.rooms.push(room.to_string());
+        }
+        self.room = room.to_string();
+    }
+
+    fn left(&mut self, room: &str) {
+        self.rooms.retain(|r| r != room);
+        self.members.remove(room);
+        self.paged.remove(room);
+        if self.room == room {
+            self.room = self.rooms.last().cloned().unwrap_or_default();
+        }
+    }
+
+    /// Name of the window currently shown: the room, or `@user` for a query.
+    fn window(&self) -> String {
+        match &self.query {
+            Some(peer) => format!("@{}", peer),
+            None => self.room.clone()
+        }
+    }
+
+    /// Updates local state (current room, name, member lists) from a server event.
+    fn apply(&mut self, event: &ServerEvent) {
+        match event {
+            ServerEvent::Joined { room } => {
+                self.joined(room);
+                config::save_last_room(room).ok();
+            }
+            ServerEvent::Left { room } => self.left(room),
+            ServerEvent::NickChanged { old, new } => {
+                if self.name == *old {
+                    self.name = new.clone();
+                }
+                if self.query.as_ref() == Some(old) {
+                    self.query = Some(new.clone());
+                }
+                if let Some(key) = self.keys.remove(&old.to_lowercase()) {
+                    self.keys.insert(new.to_lowercase(), key);
+                }
+                for users in self.members.values_mut() {
+                    for user in users.iter_mut().filter(|u| *u == old) {
+                        *user = new.clone();
+                    }
+                }
+            }
+            ServerEvent::Members { room, users, more } if self.rooms.contains(room) => {
+                let mut all = self.paged.remove(room).unwrap_or_default();
+                all.extend(users.iter().cloned());
+                if *more {
+                    self.paged.insert(room.clone(), all);
+                } else {
+                    self.members.insert(room.clone(), all);
+                }
+            }
+            ServerEvent::UserJoined { room, username } => {
+                if let Some(users) = self.members.get_mut(room) {
+                    users.push(username.clone());
+                    users.sort_by_key(|u| u.to_lowercase());
+                }
+            }
+            ServerEvent::UserLeft { room, username } => {
+                if let Some(users) = self.members.get_mut(room) {
+                    users.retain(|u| u != username);
+                }
+            }
+            ServerEvent::UserQuit { username } => {
+                for users in self.members.values_mut() {
+                    users.retain(|u| u != username);
+                }
+            }
+            ServerEvent::Moderation { room, action: ModAction::Kicked | ModAction::Banned, target, .. } => {
+                if let Some(users) = self.members.get_mut(room) {
+                    users.retain(|u| u != target);
+                }
+            }
+            _ => ()
+        }
+    }
+
+    /// Seals a direct message for `peer`, or holds it back and asks the server for their key.
+    fn direct(&mut self, peer: &str, content: String) -> Result<ClientCommand, String> {
+        if content.len() > MAX_CONTENT_SIZE {
+            return Err(format!("Messages are limited to {} bytes", MAX_CONTENT_SIZE));
+        }
+        let name = peer.to_lowercase();
+        match self.keys.get(&name) {
+            Some(key) => {
+                let sealed = self.identity.seal(key, &content).map_err(|err| format!("Can't encrypt for {}: {}", peer, err))?;
+                Ok(ClientCommand::Direct { to: peer.to_string(), sealed, color: self.remote_color })
+            }
+            None => {
+                self.waiting.entry(name).or_default().push(Waiting::Message(content));
+                Ok(ClientCommand::GetKey { username: peer.to_string() })
+            }
+        }
+    }
+
+    /// The safety number shared with `peer`, or `None` if it has to wait for their key.
+    fn verify(&mut self, peer: &str) -> Option<String> {
+        let name = peer.to_lowercase();
+        match self.keys.get(&name) {
+            Some(key) => Some(format!(
+                "Safety number with {}: {} (compare it with theirs over another channel, it changes whenever either key does)",
+                peer, e2e::safety_number(&self.identity.public_key(), key)
+            )),
+            None => {
+                self.waiting.entry(name).or_default().push(Waiting::Verify);
+                None
+            }
+        }
+    }
+
+    /// Caches a user's key and remembers it for later sessions, warning if it
+    /// differs from the one seen before, in this session or an earlier one.
+    fn remember_key(&mut self, username: &str, key: &str) -> Option<Msg> {
+        let name = username.to_lowercase();
+        let previous = self.keys.insert(name.clone(), key.to_string()).or_else(|| self.known.get(&name).cloned());
+        if self.known.get(&name).map(String::as_str) != Some(key) {
+            config::save_known_key(&self.server, &name, key).ok();
+            self.known.insert(name, key.to_string());
+        }
+        match previous {
+            Some(previous) if previous != key => Some(notice(
+                format!("Warning: the encryption key of {} has changed, /verify {} before trusting them", username, username),
+                COLOR_ERR
+            )),
+            _ => None
+        }
+    }
+
+    /// Releases whatever waited for a user's key, returning the commands to send and the lines to show.
+    fn key_arrived(&mut self, username: &str, key: Option<String>) -> (Vec<ClientCommand>, Vec<Msg>) {
+        let waiting = self.waiting.remove(&username.to_lowercase()).unwrap_or_default();
+        let key = match key {
+            Some(key) => key,
+            None if waiting.is_empty() => return (Vec::new(), Vec::new()),
+            None => {
+                let text = format!("{} is not online or has no encryption key, nothing was sent", username);
+                return (Vec::new(), vec![notice(text, COLOR_ERR)]);
+            }
+        };
+
+        let mut commands = Vec::new();
+        let mut msgs: Vec<Msg> = self.remember_key(username, &key).into_iter().collect();
+        for item in waiting {
+            match item {
+                Waiting::Message(content) => match self.direct(username, content) {
+                    Ok(command) => commands.push(command),
+                    Err(err) => msgs.push(notice(err, COLOR_ERR))
+                },
+                Waiting::Verify => {
+                    if let Some(text) = self.verify(username) {
+                        msgs.push(notice(text, COLOR_INFO));
+                    }
+                }
+            }
+        }
+        (commands, msgs)
+    }
+
+    /// Opens a direct message and shows it in the window of the other party, `@user`.
+    fn open_direct(&mut self, msg: DirectMsg) -> Vec<Msg> {
+        let outgoing = msg.sender == self.name;
+        let peer = if outgoing { msg.recipient.clone() } else { msg.sender.clone() };
+        let mut msgs = Vec::new();
+        if !outgoing {
+            msgs.extend(self.remember_key(&msg.sender, &msg.sender_key));
+        }
+        let content = match self.keys.get(&peer.to_lowercase()) {
+            Some(key) => self.identity.open(key, &msg.sealed),
+            None => Err(String::from("their key is unknown"))
+        };
+        match content {
+            Ok(content) => msgs.push(Msg {
+                room: format!("@{}", peer),
+                content,
+                sender: msg.sender,
+                color: msg.color,
+                timestamp: msg.timestamp,
+                seq: 0
+            }),
+            Err(err) => msgs.push(notice(format!("Could not open a direct message from {}: {}", msg.sender, err), COLOR_ERR))
+        }
+        msgs
+    }
 }
 
+/// Messages fetched per page when scrolling past the top of a room.
+const HISTORY_PAGE: usize = 50;
+
+#[derive(Default)]
 struct App {
 	input: String,
-	messages: Vec<Msg>
+	messages: Vec<Msg>,
+    /// Lines scrolled up from the newest message
+    scroll: usize,
+    /// Height of the message list when it was last drawn
+    height: usize,
+    /// Whether a history page has been asked for and not arrived yet
+    history_pending: bool,
+    /// Rooms whose log has been paged back to its first message
+    history_done: Vec<String>,
+    search: Option<SearchPopup>
 }
 
-impl Default for App {
-	fn default() -> App {
-		App {
-			input: String::new(),
-			messages: Vec::new()
-		}
-	}
+/// Results of `/search`, shown over the message list until one is picked or Esc is pressed.
+struct SearchPopup {
+    room: String,
+    query: String,
+    results: Vec<Msg>,
+    selected: usize
+}
+
+impl App {
+    /// Adds what a server event shows to the message list or the search popup,
+    /// returning any commands it made ready to send.
+    fn apply(&mut self, event: ServerEvent, client: &mut Client) -> Vec<ClientCommand> {
+        let count = self.messages.len();
+        let mut commands = Vec::new();
+        match event {
+            ServerEvent::HistoryPage { room, messages } => {
+                self.history_pending = false;
+                if messages.is_empty() {
+                    self.messages.insert(0, Msg { room: room.clone(), ..notice(format!("──── start of {} ────", room), COLOR_INFO) });
+                    self.history_done.push(room);
+                }
+                // Pages are older than anything shown so they go on top, where the scroll offset doesn't see them
+                self.messages.splice(0..0, messages);
+                return commands;
+            }
+            ServerEvent::SearchResults { room, query, results } => {
+                if results.is_empty() {
+                    self.messages.push(notice(format!("No messages in {} match \"{}\"", room, query), COLOR_INFO));
+                } else {
+                    let selected = results.len() - 1;
+                    self.search = Some(SearchPopup { room, query, results, selected });
+                }
+            }
+            ServerEvent::Context { .. } => {
+                self.messages.extend(event_to_msgs(event, &client.name));
+                self.scroll = 0;
+                return commands;
+            }
+            ServerEvent::Direct(msg) => self.messages.extend(client.open_direct(msg)),
+            // The server's copy of our own message, in the color we see ourselves in
+            ServerEvent::Message(msg) if msg.sender == client.name => self.messages.push(Msg { color: client.local_color, ..msg }),
+            ServerEvent::PublicKey { username, key } => {
+                let (ready, msgs) = client.key_arrived(&username, key);
+                commands = ready;
+                self.messages.extend(msgs);
+            }
+            event => self.messages.extend(event_to_msgs(event, &client.name))
+        }
+        // Keep the view still while scrolled up
+        if self.scroll > 0 {
+            self.scroll += self.messages.len() - count;
+        }
+        commands
+    }
+
+    /// Sequence number to page back from in a room: that of the oldest message shown.
+    fn oldest_seq(&self, room: &str) -> u64 {
+        self.messages.iter()
+            .filter(|m| m.room == room && m.seq > 0)
+            .map(|m| m.seq)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    fn max_scroll(&self) -> usize {
+        self.messages.len().saturating_sub(self.height)
+    }
 }
 
 pub struct Parsed {
@@ -63,26 +365,128 @@ impl Default for Parsed {
     }
 }
 
-fn request_connection(username: &str, room: String, stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+fn request_connection(username: &str, password: Option<String>, public_key: String, room: String, encoder: &mut FrameEncoder) -> Result<(), Box<dyn Error>> {
     let request = ConnectionRequest {
+        version: PROTOCOL_VERSION,
+        capabilities: protocol::CAPABILITIES.to_vec(),
         username: username.to_string(),
-        room: room
+        room,
+        password,
+        public_key: Some(public_key)
     };
 
-    let serialized_request = serde_json::to_string(&request)?;
+    let request_string = serde_json::to_vec(&ClientCommand::Hello(request))?;
+
+    encoder.push(&request_string)?;
+    Ok(())
+}
+
+fn notice(content: String, color: Color) -> Msg {
+    Msg {
+        content,
+        sender: String::from("*"),
+        color,
+        ..Msg::default()
+    }
+}
 
-    let request_string = serde_json::to_string(&MessageWrapper {
-        msg_type: MsgType::ConnectionRequest,
-        msg: serialized_request
-    })?;
+/// Turns a server event into the lines shown in the message list.
+fn event_to_msgs(event: ServerEvent, own_name: &str) -> Vec<Msg> {
+    let msg = match event {
+        ServerEvent::Welcome { version, capabilities } => {
+            let capabilities = if capabilities.is_empty() {
+                String::from("none")
+            } else {
+                capabilities.iter().map(|c| format!("{:?}", c)).collect::<Vec<String>>().join(", ")
+            };
+            notice(format!("Connected (protocol {}, capabilities: {})", version, capabilities), COLOR_INFO)
+        }
+        ServerEvent::Rejected { reason } => notice(format!("Server rejected the connection: {}", reason), COLOR_ERR),
+        ServerEvent::Message(msg) => msg,
+        ServerEvent::Backlog { room, messages } => {
+            let mut msgs = vec![notice(format!("──── history of {} ────", room), COLOR_INFO)];
+            msgs.extend(messages);
+            msgs.push(notice(String::from("──── end of history ────"), COLOR_INFO));
+            return msgs;
+        }
+        ServerEvent::Context { room, seq, messages } => {
+            let mut msgs = vec![notice(format!("──── context in {} ────", room), COLOR_INFO)];
+            msgs.extend(messages.into_iter().map(|mut m| {
+                if m.seq == seq {
+                    m.content = format!("» {}", m.content);
+                }
+                m
+            }));
+            msgs.push(notice(String::from("──── end of context ────"), COLOR_INFO));
+            return msgs;
+        }
+        // These go to the App rather than the message list
+        ServerEvent::HistoryPage { .. } | ServerEvent::SearchResults { .. } | ServerEvent::Direct(_) | ServerEvent::PublicKey { .. } => return Vec::new(),
+        ServerEvent::Joined { room } => notice(format!("Joined room {}", room), COLOR_INFO),
+        ServerEvent::Left { room } => notice(format!("Left room {}", room), COLOR_INFO),
+        ServerEvent::Rooms { rooms, .. } => notice(format!("Rooms: {}", rooms.join(", ")), COLOR_INFO),
+        ServerEvent::Registered { username } => notice(format!("Registered account {}, connect with --login to use it", username), COLOR_INFO),
+        ServerEvent::NickChanged { old, new } => notice(format!("{} is now known as {}", old, new), COLOR_INFO),
+        ServerEvent::Members { room, users, .. } => notice(format!("Users in {}: {}", room, users.join(", ")), COLOR_INFO),
+        ServerEvent::UserJoined { room, username } => notice(format!("{} joined {}", username, room), COLOR_INFO),
+        ServerEvent::UserLeft { room, username } => notice(format!("{} left {}", username, room), COLOR_INFO),
+        ServerEvent::UserQuit { username } => notice(format!("{} disconnected", username), COLOR_INFO),
+        ServerEvent::Notice { content } => notice(content, COLOR_INFO),
+        ServerEvent::Throttled { action, reason } => {
+            let content = match action {
+                Throttle::Warning => format!("Slow down! {}, that message was dropped", reason),
+                Throttle::Mute { seconds } => format!("Muted for {} seconds: {}", seconds, reason),
+                Throttle::Disconnect => format!("Disconnected for flooding: {}", reason)
+            };
+            notice(content, COLOR_ERR)
+        }
+        ServerEvent::Moderation { room, action, target, by, reason, until } => {
+            let what = match action {
+                ModAction::Kicked => "kicked from",
+                ModAction::Banned => "banned from",
+                ModAction::Unbanned => "unbanned from",
+                ModAction::Muted => "muted in",
+                ModAction::Unmuted => "unmuted in"
+            };
+            let mut content = format!("{} was {} {} by {}", target, what, room, by);
+            if let Some(until) = until {
+                let local: DateTime<Local> = DateTime::from(until);
+                content += &format!(" until {}", local.format("%Y-%m-%d %H:%M"));
+            }
+            if let Some(reason) = reason {
+                content += &format!(": {}", reason);
+            }
+            let color = if target.eq_ignore_ascii_case(own_name) { COLOR_ERR } else { COLOR_INFO };
+            notice(content, color)
+        }
+        ServerEvent::RoleChanged { room, username, role, by } => notice(format!("{} made {} {} of {}", by, username, role, room), COLOR_INFO),
+        ServerEvent::Error { reason } => notice(reason, COLOR_ERR),
+        ServerEvent::ProtocolError { reason } => notice(format!("Server closed the connection: {}", reason), COLOR_ERR),
+        ServerEvent::Shutdown { reason, restart_in } => {
+            let mut content = String::from("The server is shutting down");
+            if let Some(reason) = reason {
+                content += &format!(": {}", reason);
+            }
+            match restart_in {
+                Some(seconds) if seconds >= 120 => content += &format!(", back in about {} minutes", seconds / 60),
+                Some(seconds) => content += &format!(", back in about {} seconds", seconds),
+                None => ()
+            }
+            notice(content, COLOR_ERR)
+        }
+    };
+    vec![msg]
+}
 
-    stream.write_all(&size_of_val(request_string.as_bytes()).to_be_bytes())?;
-    stream.write_all(request_string.as_bytes())?;
-    return Ok(());
+/// How the server's key compares to the one remembered in known_hosts.
+enum HostKey {
+    /// Same key as before, or the key was checked against a CA or pin instead
+    Trusted,
+    New(String),
+    Changed { known: String, offered: String }
 }
 
-// TODO implement config files
-pub fn start(addr: String, username: String, config: Config) -> Result<(), Box<dyn Error>> {
+pub fn start(addr: String, trust: Option<tls::Trust>, username: String, password: Option<String>, room: String, config: Config) -> Result<(), Box<dyn Error>> {
 	ctrlc::set_handler(move || {
 		println!("Exiting...");
 		quit();
@@ -90,10 +494,37 @@ pub fn start(addr: String, username: String, config: Config) -> Result<(), Box<d
 
     let username: &str = &username;
 
-	let client = Arc::new(Mutex::new(Client::new(username.to_string())));
+	let identity = Identity::load_or_create().unwrap_or_else(|err| {
+		println!("Failed to load the identity key, using a temporary one: {}", err);
+		Identity::generate()
+	});
+	let public_key = identity.public_key();
+	let client = Arc::new(Mutex::new(Client::new(username.to_string(), &config, identity, addr.clone())));
 
-	let mut stream = TcpStream::connect(addr).expect("Failed to connect to server.");
-	stream.set_nonblocking(true)?;
+	let sock = TcpStream::connect(&addr).expect("Failed to connect to server.");
+	let mut host_key = HostKey::Trusted;
+	let mut stream = match &trust {
+		Some(trust) => {
+			let host = addr.rsplit_once(':').map_or(addr.as_str(), |(host, _)| host).trim_matches(|c| c == '[' || c == ']');
+			let mut stream = tls::connect(tls::client_config(trust)?, host, sock)?;
+			// Finished up front so the key is checked before anything, like a password, is sent
+			if let Err(err) = stream.handshake() {
+				println!("TLS handshake with {} failed: {}", addr, err);
+				exit(1);
+			}
+			if let tls::Trust::FirstUse = trust {
+				let offered = stream.peer_fingerprint().unwrap_or_default();
+				host_key = match config::load_known_host(&addr) {
+					Some(known) if tls::same_fingerprint(&known, &offered) => HostKey::Trusted,
+					Some(known) => HostKey::Changed { known, offered },
+					None => HostKey::New(offered)
+				};
+			}
+			stream
+		}
+		None => Stream::Plain(sock)
+	};
+	stream.get_ref().set_nonblocking(true)?;
 
 	let stdout = io::stdout();
 	
@@ -103,69 +534,93 @@ pub fn start(addr: String, username: String, config: Config) -> Result<(), Box<d
 	// let events = Events::new();
 
 	let app = Arc::new(Mutex::new(App::default()));
+
+    // Keys like PageUp only reach us without line buffering
+    enable_raw_mode()?;
+    terminal.clear().unwrap();
+
+    match host_key {
+        HostKey::Trusted => (),
+        HostKey::New(fingerprint) => {
+            config::save_known_host(&addr, &fingerprint).ok();
+            let text = format!("First connection to {}, remembering its key fingerprint {}", addr, fingerprint);
+            app.lock().unwrap().messages.push(notice(text, COLOR_INFO));
+        }
+        HostKey::Changed { known, offered } => {
+            if !confirm_changed_key(&mut terminal, &addr, &known, &offered)? {
+                terminal.clear().unwrap();
+                disable_raw_mode()?;
+                return Ok(());
+            }
+            config::save_known_host(&addr, &offered).ok();
+            let text = format!("Now trusting the new key of {}: {}", addr, offered);
+            app.lock().unwrap().messages.push(notice(text, COLOR_ERR));
+        }
+    }
 	
-    let (tx, rx) = mpsc::channel::<Msg>();
-    let (tx_i, rx_i) = mpsc::channel::<Msg>();
+    let (tx, rx) = mpsc::channel::<ClientCommand>();
+    let (tx_i, rx_i) = mpsc::channel::<ServerEvent>();
 
     let shared_tx = Arc::new(Mutex::new(tx));
 
 
-    // TODO this should default to _default, but otherwise should use last joined room or specified in :open command
-    request_connection(username, String::from("_default"), &mut stream)?;
+    let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE);
+    let mut encoder = FrameEncoder::new(DEFAULT_MAX_FRAME_SIZE);
+
+    request_connection(username, password, public_key, room, &mut encoder)?;
 
     thread::spawn(move || loop {
-		let mut buf_sz = [0; std::mem::size_of::<usize>()];
-		match stream.read_exact(&mut buf_sz) {
-			Ok(_) => {
-				let json_sz = usize::from_be_bytes(buf_sz);
-                let mut msg_buf: Vec<u8> = vec![0; json_sz];
-                match stream.read_exact(&mut msg_buf){
-                    Ok(_) => {
-                        let msg: Msg = serde_json::from_str(String::from_utf8(msg_buf).unwrap().as_str()).unwrap();
-                        tx_i.send(msg).unwrap();
-                    },
-                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-                    Err(_) => {
-                        println!("Error while reading json data from server");
+		match decoder.read_frame(&mut stream) {
+			Ok(Some(frame)) => {
+                // Events from a newer server that this build doesn't know are skipped
+                if let Ok(event) = serde_json::from_slice::<ServerEvent>(&frame) {
+                    // Leaving right away lets the server finish shutting down sooner
+                    let shutdown = matches!(event, ServerEvent::Shutdown { .. });
+                    tx_i.send(event).ok();
+                    if shutdown {
                         break;
                     }
                 }
+                continue;
 			},
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-    		Err(_) => {
-				println!("Connection with server was severed!");
+            Ok(None) => (),
+    		Err(FrameError::Closed) => {
+				tx_i.send(ServerEvent::Error { reason: String::from("Connection with server was severed!") }).ok();
 				break;
 			}
+            Err(err) => {
+                tx_i.send(ServerEvent::Error { reason: format!("Error while reading data from server: {}", err) }).ok();
+                break;
+            }
 		}
 
         match rx.try_recv() {
-            Ok(msg) => {
-                let outbound_json = serde_json::to_string(&msg).unwrap();
-
-				let outbound = outbound_json.as_bytes();
-
-                stream.write_all(&size_of_val(outbound).to_be_bytes()).unwrap();
-                stream.write_all(&outbound).unwrap();
+            Ok(command) => {
+                let outbound = serde_json::to_vec(&command).unwrap();
+                if let Err(err) = encoder.push(&outbound) {
+                    tx_i.send(ServerEvent::Error { reason: format!("Not sent: {}", err) }).ok();
+                }
             },
             Err(mpsc::TryRecvError::Empty) => (),
             Err(mpsc::TryRecvError::Disconnected) => break
         }
+
+        if encoder.flush_to(&mut stream).is_err() {
+            tx_i.send(ServerEvent::Error { reason: String::from("Connection with server was severed!") }).ok();
+            break;
+        }
         sleep(Duration::from_millis(100));
 	});
 
-    terminal.clear().unwrap();
-
     loop {
 		terminal.draw(|f| {
             let mut app_t = app.lock().unwrap();
-            match rx_i.try_recv() {
-                Ok(msg) => {
-                    if msg.sender != client.lock().unwrap().name {
-                        app_t.messages.push(msg);
-                    }
+            while let Ok(event) = rx_i.try_recv() {
+                let mut cl = client.lock().unwrap();
+                cl.apply(&event);
+                for command in app_t.apply(event, &mut cl) {
+                    shared_tx.lock().unwrap().send(command).ok();
                 }
-                Err(mpsc::TryRecvError::Empty) => (),
-                Err(mpsc::TryRecvError::Disconnected) => return
             }
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
@@ -180,53 +635,152 @@ pub fn start(addr: String, username: String, config: Config) -> Result<(), Box<d
                 )
                 .split(f.size());
 
-            let input = Paragraph::new(app_t.input.as_ref())
+            // Keep passwords off the screen while they are typed
+            let shown = match app_t.input.strip_prefix("/register ") {
+                Some(password) => format!("/register {}", "*".repeat(password.chars().count())),
+                None => app_t.input.clone()
+            };
+            let input = Paragraph::new(shown.as_ref())
                 .style(Style::default())
                 .block(Block::default().borders(Borders::NONE));
 
             f.render_widget(input, chunks[1]);
             f.set_cursor(
                 // Put cursor past the end of the input text
-                chunks[1].x + app_t.input.width() as u16, //+ 1,
+                chunks[1].x + shown.width() as u16, //+ 1,
                 // Move one line down, from the border to the input line
                 chunks[1].y //+ 1,
             );
+            let cl = client.lock().unwrap();
+            let current_room = cl.room.clone();
+            let current_window = cl.window();
+            let message_area = if cl.show_members {
+                let columns = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Min(1), Constraint::Length(20)].as_ref())
+                    .split(chunks[0]);
+                let users: Vec<ListItem> = cl.members.get(&current_room)
+                    .map(|users| users.iter().map(|u| ListItem::new(u.as_str())).collect())
+                    .unwrap_or_default();
+                let members = List::new(users)
+                    .block(Block::default().borders(Borders::LEFT).title(current_window.as_str()));
+                f.render_widget(members, columns[1]);
+                columns[0]
+            } else {
+                chunks[0]
+            };
+            drop(cl);
+            app_t.height = message_area.height as usize;
+            app_t.scroll = app_t.scroll.min(app_t.max_scroll());
+            let end = app_t.messages.len() - app_t.scroll;
+            let start = end.saturating_sub(app_t.height);
             let messages: Vec<ListItem> = app_t
-                .messages
+                .messages[start..end]
                 .iter()
-                .enumerate()
-                .map(|(_i, m)| {
+                .map(|m| {
                     let style = Style::default().fg(m.color);
                     let local: DateTime<Local> = DateTime::from(m.timestamp);
                     let time = local.format("%H:%M:%S").to_string();
-                    let content = vec![Spans::from(Span::styled(format!("{} {}: {}", time,  m.sender, m.content), style))];
+                    // Messages from windows other than the current one are tagged with their room or @user
+                    let room = if m.room.is_empty() || m.room == current_window {
+                        String::new()
+                    } else {
+                        format!("[{}] ", m.room)
+                    };
+                    let content = vec![Spans::from(Span::styled(format!("{} {}{}: {}", time, room, m.sender, m.content), style))];
                     ListItem::new(content)
                 })
                 .collect();
             let messages =
                 List::new(messages).block(Block::default().borders(Borders::NONE)); //.title("Messages"));
-            f.render_widget(messages, chunks[0]);
+            f.render_widget(messages, message_area);
+
+            if let Some(search) = &app_t.search {
+                let area = centered(f.size(), 80, 60);
+                let results: Vec<ListItem> = search.results
+                    .iter()
+                    .map(|m| {
+                        let local: DateTime<Local> = DateTime::from(m.timestamp);
+                        ListItem::new(format!("{} {}: {}", local.format("%Y-%m-%d %H:%M"), m.sender, m.content))
+                    })
+                    .collect();
+                let title = format!("\"{}\" in {} (Enter jumps, Esc closes)", search.query, search.room);
+                let results = List::new(results)
+                    .block(Block::default().borders(Borders::ALL).title(title))
+                    .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+                let mut state = ListState::default();
+                state.select(Some(search.selected));
+                f.render_widget(Clear, area);
+                f.render_stateful_widget(results, area, &mut state);
+            }
             drop(app_t);
         })?;
 
+        // Redraw periodically so server events show up without a keypress
+        if !poll(Duration::from_millis(100))? {
+            continue;
+        }
+
         let mut app_t = app.lock().unwrap();
         
         // Handle input
         match read()? {
+            Event::Key(event) if app_t.search.is_some() => {
+                let search = app_t.search.as_mut().unwrap();
+                match event.code {
+                    KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => break,
+                    KeyCode::Up => search.selected = search.selected.saturating_sub(1),
+                    KeyCode::Down => search.selected = (search.selected + 1).min(search.results.len() - 1),
+                    KeyCode::Enter => {
+                        let seq = search.results[search.selected].seq;
+                        let room = search.room.clone();
+                        app_t.search = None;
+                        shared_tx.lock().unwrap().send(ClientCommand::Context { room, seq }).ok();
+                    }
+                    KeyCode::Esc => app_t.search = None,
+                    _ => {}
+                }
+            },
             Event::Key(event) => {
                 match event.code {
-                    KeyCode::Char('c') => break,
+                    KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => break,
                     KeyCode::Enter => {
                         let msg = app_t.input.drain(..).collect();
                         let parse = parse_message(msg, shared_tx.lock().unwrap(), client.lock().unwrap());
                         if parse.should_print {
                             let cl = client.lock().unwrap();
-                            app_t.messages.push(Msg{sender: (&cl.name).to_string(), content: parse.content, color: parse.color, timestamp: Utc::now()});
+                            app_t.messages.push(Msg{sender: cl.name.to_string(), content: parse.content, color: parse.color, ..Msg::default()});
                         }
                     },
                     KeyCode::Backspace => {
                         app_t.input.pop();
                     },
+                    KeyCode::PageUp => {
+                        let page = app_t.height.saturating_sub(1).max(1);
+                        app_t.scroll += page;
+                        if app_t.scroll >= app_t.max_scroll() {
+                            app_t.scroll = app_t.max_scroll();
+                            // Past the top of what we have: fetch an older page of the current room
+                            let cl = client.lock().unwrap();
+                            if cl.query.is_none() && cl.rooms.contains(&cl.room)
+                                && !app_t.history_pending && !app_t.history_done.contains(&cl.room) {
+                                app_t.history_pending = true;
+                                shared_tx.lock().unwrap().send(ClientCommand::History {
+                                    room: cl.room.clone(),
+                                    before: app_t.oldest_seq(&cl.room),
+                                    limit: HISTORY_PAGE
+                                }).ok();
+                            }
+                        }
+                    },
+                    KeyCode::PageDown => {
+                        let page = app_t.height.saturating_sub(1).max(1);
+                        app_t.scroll = app_t.scroll.saturating_sub(page);
+                    },
+                    KeyCode::F(2) => {
+                        let mut cl = client.lock().unwrap();
+                        cl.show_members = !cl.show_members;
+                    },
                     KeyCode::Char(c) => {
                         app_t.input.push(c);
                     }
@@ -239,13 +793,56 @@ pub fn start(addr: String, username: String, config: Config) -> Result<(), Box<d
 	}
     terminal.clear().unwrap();
     drop(terminal);
+    disable_raw_mode()?;
     Ok(())
 }
 
-fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: MutexGuard<Client>) -> Parsed {
+/// Fills the screen with a warning about a changed server key, and only
+/// returns true if the user types `yes` to trust the new one.
+fn confirm_changed_key<B: Backend>(terminal: &mut Terminal<B>, addr: &str, known: &str, offered: &str) -> Result<bool, Box<dyn Error>> {
+    let mut input = String::new();
+    loop {
+        terminal.draw(|f| {
+            let alert = Style::default().fg(COLOR_ERR).add_modifier(Modifier::BOLD);
+            let text = vec![
+                Spans::from(Span::styled("WARNING: THE SERVER'S KEY HAS CHANGED!", alert)),
+                Spans::from(""),
+                Spans::from(format!("{} presented a different key than on earlier connections.", addr)),
+                Spans::from("Someone could be intercepting this connection, or the server was given a new key."),
+                Spans::from(""),
+                Spans::from(format!("Known key:   {}", known)),
+                Spans::from(format!("Offered key: {}", offered)),
+                Spans::from(""),
+                Spans::from("Nothing has been sent to the server yet."),
+                Spans::from(Span::styled("Type yes and press Enter to trust the new key, anything else quits.", alert)),
+                Spans::from(""),
+                Spans::from(format!("> {}", input)),
+            ];
+            let block = Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(COLOR_ERR))
+                .title(Span::styled(" Server key mismatch ", alert));
+            f.render_widget(Paragraph::new(text).block(block).wrap(Wrap { trim: false }), f.size());
+        })?;
+
+        if let Event::Key(event) = read()? {
+            match event.code {
+                KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => return Ok(false),
+                KeyCode::Esc => return Ok(false),
+                KeyCode::Enter => return Ok(input.trim() == "yes"),
+                KeyCode::Backspace => {
+                    input.pop();
+                },
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<ClientCommand>>, mut client: MutexGuard<Client>) -> Parsed {
     let msg = msg.trim().to_string();
-    if msg.starts_with('/') {
-        let msg: &str = msg[1..].as_ref();
+    if let Some(msg) = msg.strip_prefix('/') {
         let cmd = msg.split(' ').collect::<Vec<&str>>();
 
         match cmd[0] {
@@ -253,7 +850,7 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
             if cmd.len() != 2 {
                 Parsed {
                     should_print: true,
-                    content: format!("Available commands: /help, /nick <nickname>, /color <color>"),
+                    content: String::from(HELP),
                     color: COLOR_INFO
                 }
             } else {
@@ -261,7 +858,7 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
                     "nick" => {
                         Parsed {
                             should_print: true,
-                            content: format!("Usage of nick: /nick <nickname>"),
+                            content: String::from("Usage of nick: /nick <nickname>"),
                             color: COLOR_INFO
                         }
                     },
@@ -272,10 +869,59 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
                             color: COLOR_INFO
                         }
                     },
+                    "msg" | "query" => {
+                        Parsed {
+                            should_print: true,
+                            content: String::from("/msg <user> <text> sends a private message, /query <user> opens a window where everything you type goes to that user, /query closes it. Private messages are end-to-end encrypted, /verify <user> shows the safety number to compare with them"),
+                            color: COLOR_INFO
+                        }
+                    },
+                    "search" => {
+                        Parsed {
+                            should_print: true,
+                            content: String::from("/search <text> looks through the current room's history; pick a result with Up/Down and Enter to see it in context. PageUp/PageDown scroll, fetching older history at the top"),
+                            color: COLOR_INFO
+                        }
+                    },
+                    "verify" => {
+                        Parsed {
+                            should_print: true,
+                            content: String::from("/verify <user> shows the safety number of your and their keys; if it matches what they see, nobody can read your private messages in between"),
+                            color: COLOR_INFO
+                        }
+                    },
+                    "register" => {
+                        Parsed {
+                            should_print: true,
+                            content: String::from("/register <password> registers your current name so nobody else can use it; connect with --login afterwards"),
+                            color: COLOR_INFO
+                        }
+                    },
+                    "kick" | "ban" | "unban" | "mute" | "unmute" | "op" | "deop" | "owner" => {
+                        Parsed {
+                            should_print: true,
+                            content: String::from("Operators of the current room can /kick <user> [reason], /ban <user|ip> [duration] [reason], /unban <user|ip>, /mute <user> [duration] [reason] and /unmute <user>; durations look like 30s, 10m, 2h or 7d and bans without one are permanent. Its owner can /op and /deop operators and hand the room over with /owner <user>"),
+                            color: COLOR_INFO
+                        }
+                    },
+                    "who" | "sidebar" => {
+                        Parsed {
+                            should_print: true,
+                            content: String::from("/who [room] lists the users in the current or given room, /sidebar (or F2) toggles the member list"),
+                            color: COLOR_INFO
+                        }
+                    },
+                    "rooms" | "create" | "join" | "open" | "leave" => {
+                        Parsed {
+                            should_print: true,
+                            content: String::from("/rooms lists rooms, /create <room> creates and joins one, /join <room> joins one and makes it current, /open <room> switches to a room (joining it if needed), /leave [room] leaves the current or given room"),
+                            color: COLOR_INFO
+                        }
+                    },
                     _ => {
                         Parsed {
                             should_print: true,
-                            content: format!("Available commands: /help, /nick <nickname>, /color <color>"),
+                            content: String::from(HELP),
                             color: COLOR_INFO
                         }
                     }
@@ -286,31 +932,206 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
             if cmd.len() != 2 {
                 return Parsed {
                     should_print: true,
-                    content: format!("Incorrect usage of command! /nick <name>"),
+                    content: String::from("Incorrect usage of command! /nick <name>"),
                     color: COLOR_ERR
                 }
             }
-            let arg = cmd[1];
-            client.name = String::from(arg);
-            return Parsed {
+            // The name only changes once the server accepts it
+            tx.send(ClientCommand::Nick { username: cmd[1].to_string() }).ok();
+            Parsed::default()
+        }
+        "info" => {
+            Parsed {
                 should_print: true,
-                content: format!("Changed name to: {}", &client.name),
+                content: (*client.name).to_string(),
                 color: COLOR_INFO
             }
         }
-        "info" => {
-            return Parsed {
+        "open" => {
+            if cmd.len() != 2 {
+                return Parsed {
+                    should_print: true,
+                    content: String::from("Incorrect usage of command! /open <room>"),
+                    color: COLOR_ERR
+                }
+            }
+            let room = cmd[1].to_string();
+            if !client.rooms.contains(&room) {
+                // Switching happens once the server confirms the join
+                tx.send(ClientCommand::Join { room }).ok();
+                return Parsed::default();
+            }
+            config::save_last_room(&room).ok();
+            client.room = room;
+            Parsed {
                 should_print: true,
-                content: (*client.name).to_string(),
+                content: format!("Switched to room {}", client.room),
+                color: COLOR_INFO
+            }
+        }
+        "rooms" => {
+            tx.send(ClientCommand::ListRooms).ok();
+            Parsed::default()
+        }
+        "create" | "join" => {
+            if cmd.len() != 2 {
+                return Parsed {
+                    should_print: true,
+                    content: format!("Incorrect usage of command! /{} <room>", cmd[0]),
+                    color: COLOR_ERR
+                }
+            }
+            let room = cmd[1].to_string();
+            if cmd[0] == "create" {
+                tx.send(ClientCommand::CreateRoom { room }).ok();
+            } else {
+                tx.send(ClientCommand::Join { room }).ok();
+            }
+            Parsed::default()
+        }
+        "msg" => {
+            if cmd.len() < 3 {
+                return Parsed {
+                    should_print: true,
+                    content: String::from("Incorrect usage of command! /msg <user> <text>"),
+                    color: COLOR_ERR
+                }
+            }
+            match client.direct(cmd[1], cmd[2..].join(" ")) {
+                Ok(command) => {
+                    tx.send(command).ok();
+                    Parsed::default()
+                }
+                Err(err) => Parsed {
+                    should_print: true,
+                    content: err,
+                    color: COLOR_ERR
+                }
+            }
+        }
+        "verify" => {
+            if cmd.len() != 2 {
+                return Parsed {
+                    should_print: true,
+                    content: String::from("Incorrect usage of command! /verify <user>"),
+                    color: COLOR_ERR
+                }
+            }
+            match client.verify(cmd[1]) {
+                Some(text) => Parsed {
+                    should_print: true,
+                    content: text,
+                    color: COLOR_INFO
+                },
+                None => {
+                    tx.send(ClientCommand::GetKey { username: cmd[1].to_string() }).ok();
+                    Parsed::default()
+                }
+            }
+        }
+        "query" => {
+            client.query = cmd.get(1).map(|peer| peer.to_string());
+            Parsed {
+                should_print: true,
+                content: match &client.query {
+                    Some(peer) => format!("Opened query with {}, /query closes it", peer),
+                    None => format!("Back in room {}", client.room)
+                },
                 color: COLOR_INFO
             }
         }
-        "open" => todo!(),
+        "who" => {
+            let room = match cmd.get(1) {
+                Some(room) => room.to_string(),
+                None => client.room.clone()
+            };
+            tx.send(ClientCommand::Who { room }).ok();
+            Parsed::default()
+        }
+        "register" => {
+            if cmd.len() != 2 {
+                return Parsed {
+                    should_print: true,
+                    content: String::from("Incorrect usage of command! /register <password>"),
+                    color: COLOR_ERR
+                }
+            }
+            tx.send(ClientCommand::Register { password: cmd[1].to_string() }).ok();
+            Parsed::default()
+        }
+        "kick" | "ban" | "unban" | "mute" | "unmute" | "op" | "deop" | "owner" => {
+            if cmd.len() < 2 {
+                let usage = match cmd[0] {
+                    "kick" => "/kick <user> [reason]",
+                    "ban" => "/ban <user|ip> [duration] [reason]",
+                    "unban" => "/unban <user|ip>",
+                    "mute" => "/mute <user> [duration] [reason]",
+                    _ => "/<unmute|op|deop|owner> <user>"
+                };
+                return Parsed {
+                    should_print: true,
+                    content: format!("Incorrect usage of command! {}", usage),
+                    color: COLOR_ERR
+                }
+            }
+            if client.rooms.is_empty() {
+                return Parsed {
+                    should_print: true,
+                    content: String::from("You are not in any room. Try /join <room>"),
+                    color: COLOR_ERR
+                }
+            }
+            let room = client.room.clone();
+            let target = cmd[1].to_string();
+            let (seconds, reason) = moderation::duration_and_text(&cmd[2..]);
+            let command = match cmd[0] {
+                "kick" => ClientCommand::Kick { room, username: target, reason: cmd.get(2).map(|_| cmd[2..].join(" ")) },
+                "ban" => ClientCommand::Ban { room, target, seconds, reason },
+                "unban" => ClientCommand::Unban { room, target },
+                "mute" => ClientCommand::Mute { room, username: target, seconds, reason },
+                "unmute" => ClientCommand::Unmute { room, username: target },
+                "op" => ClientCommand::SetRole { room, username: target, role: Role::Operator },
+                "deop" => ClientCommand::SetRole { room, username: target, role: Role::Member },
+                _ => ClientCommand::SetRole { room, username: target, role: Role::Owner }
+            };
+            tx.send(command).ok();
+            Parsed::default()
+        }
+        "search" => {
+            if cmd.len() < 2 {
+                return Parsed {
+                    should_print: true,
+                    content: String::from("Incorrect usage of command! /search <text>"),
+                    color: COLOR_ERR
+                }
+            }
+            if !client.rooms.contains(&client.room) {
+                return Parsed {
+                    should_print: true,
+                    content: String::from("You are not in any room. Try /join <room>"),
+                    color: COLOR_ERR
+                }
+            }
+            tx.send(ClientCommand::Search { room: client.room.clone(), query: cmd[1..].join(" ") }).ok();
+            Parsed::default()
+        }
+        "sidebar" => {
+            client.show_members = !client.show_members;
+            Parsed::default()
+        }
+        "leave" => {
+            let room = match cmd.get(1) {
+                Some(room) => room.to_string(),
+                None => client.room.clone()
+            };
+            tx.send(ClientCommand::Leave { room }).ok();
+            Parsed::default()
+        }
         "remote-color" => {
             if cmd.len() != 2 {
                 return Parsed {
                     should_print: true,
-                    content: format!("Incorrect usage of command! /remote-color <color>"),
+                    content: String::from("Incorrect usage of command! /remote-color <color>"),
                     color: COLOR_ERR
                 }
             }
@@ -319,7 +1140,7 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
             let mut color = Color::White;
             match color_from_name(arg) {
                 Ok(_color) => {
-                    out_str = String::from("Changed color to ".to_owned() + arg);
+                    out_str = "Changed color to ".to_owned() + arg;
                     color = _color;
                 }
                 Err(e) => {
@@ -327,7 +1148,7 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
                 }
             }
             client.remote_color = color;
-            return Parsed {
+            Parsed {
                 should_print: true,
                 content: out_str,
                 color,
@@ -346,7 +1167,7 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
             let mut color = Color::White;
             match color_from_name(arg) {
                 Ok(_color) => {
-                    out_str = String::from("Changed color to ".to_owned() + arg);
+                    out_str = "Changed color to ".to_owned() + arg;
                     color = _color;
                 }
                 Err(e) => {
@@ -354,7 +1175,7 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
                 }
             }
             client.local_color = color;
-            return Parsed {
+            Parsed {
                 should_print: true,
                 content: out_str,
                 color,
@@ -369,19 +1190,51 @@ fn parse_message(msg: String, tx: MutexGuard<mpsc::Sender<Msg>>, mut client: Mut
         }
     }
     } else {
-        tx.send(Msg{content: msg.clone().to_owned(), 
-            sender: (*client.name).to_string().to_owned(), 
-            color: client.remote_color, 
-            timestamp: Utc::now()})
-            .unwrap();
-        Parsed {
-            should_print: true,
-            content: msg,
-            color: client.local_color
+        if let Some(peer) = client.query.clone() {
+            // Shown once the server echoes it back
+            return match client.direct(&peer, msg) {
+                Ok(command) => {
+                    tx.send(command).ok();
+                    Parsed::default()
+                }
+                Err(err) => Parsed {
+                    should_print: true,
+                    content: err,
+                    color: COLOR_ERR
+                }
+            };
+        }
+        if client.rooms.is_empty() {
+            return Parsed {
+                should_print: true,
+                content: String::from("You are not in any room. Try /join <room>"),
+                color: COLOR_ERR
+            }
         }
+        if msg.len() > MAX_CONTENT_SIZE {
+            return Parsed {
+                should_print: true,
+                content: format!("Messages are limited to {} bytes", MAX_CONTENT_SIZE),
+                color: COLOR_ERR
+            }
+        }
+        tx.send(ClientCommand::Message {
+            room: client.room.clone(),
+            content: msg,
+            color: client.remote_color
+        }).ok();
+        // Shown once the server echoes it back, so a refused message never looks sent
+        Parsed::default()
     }
 }
 
+/// A rectangle of the given percentages of `area`, centered in it.
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let w = area.width * width / 100;
+    let h = area.height * height / 100;
+    Rect::new(area.x + (area.width - w) / 2, area.y + (area.height - h) / 2, w, h)
+}
+
 fn color_from_name(color: &str) -> Result<Color, String> {
     match color.to_lowercase().as_str() {
         "black"        => {Ok(Color::Black)}
@@ -407,6 +1260,7 @@ fn color_from_name(color: &str) -> Result<Color, String> {
 }
 
 fn quit() {
+    disable_raw_mode().ok();
     println!("\x1B[2J\x1B[1;1H");
     exit(0);
 }
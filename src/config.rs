@@ -1,23 +1,202 @@
+use std::{collections::HashMap, env, fs, io, net::SocketAddr, path::{Path, PathBuf}};
+
 use serde::Deserialize;
 use gethostname::gethostname;
 
+const LAST_ROOM_FILE: &str = "last_room";
+const KNOWN_HOSTS_FILE: &str = "known_hosts";
+const KNOWN_KEYS_FILE: &str = "known_keys";
 
-#[derive(Deserialize)]
+/// `$XDG_CONFIG_HOME/svchat`, falling back to `~/.config/svchat`.
+pub fn config_dir() -> Option<PathBuf> {
+	let base = match env::var_os("XDG_CONFIG_HOME") {
+		Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+		_ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+	};
+	Some(base.join("svchat"))
+}
+
+/// `$XDG_DATA_HOME/svchat`, falling back to `~/.local/share/svchat`.
+pub fn data_dir() -> Option<PathBuf> {
+	let base = match env::var_os("XDG_DATA_HOME") {
+		Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+		_ => PathBuf::from(env::var_os("HOME")?).join(".local").join("share"),
+	};
+	Some(base.join("svchat"))
+}
+
+/// Room the client was last in, if one was saved.
+pub fn load_last_room() -> Option<String> {
+	let room = fs::read_to_string(config_dir()?.join(LAST_ROOM_FILE)).ok()?;
+	let room = room.trim();
+	if room.is_empty() {
+		None
+	} else {
+		Some(room.to_string())
+	}
+}
+
+pub fn save_last_room(room: &str) -> io::Result<()> {
+	let dir = config_dir().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+	fs::create_dir_all(&dir)?;
+	fs::write(dir.join(LAST_ROOM_FILE), room)
+}
+
+/// Key fingerprint remembered for a server address, one `address fingerprint` pair per line.
+pub fn load_known_host(addr: &str) -> Option<String> {
+	let hosts = fs::read_to_string(config_dir()?.join(KNOWN_HOSTS_FILE)).ok()?;
+	hosts.lines()
+		.filter_map(|line| line.split_once(' '))
+		.find(|(host, _)| *host == addr)
+		.map(|(_, fingerprint)| fingerprint.trim().to_string())
+}
+
+/// Remembers the key fingerprint of a server address, replacing any earlier one.
+pub fn save_known_host(addr: &str, fingerprint: &str) -> io::Result<()> {
+	let dir = config_dir().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+	fs::create_dir_all(&dir)?;
+	let path = dir.join(KNOWN_HOSTS_FILE);
+	let hosts = match fs::read_to_string(&path) {
+		Ok(hosts) => hosts,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+		Err(err) => return Err(err),
+	};
+	let mut lines: Vec<&str> = hosts.lines().filter(|line| line.split(' ').next() != Some(addr)).collect();
+	let entry = format!("{} {}", addr, fingerprint);
+	lines.push(&entry);
+	fs::write(path, lines.join("\n") + "\n")
+}
+
+/// Encryption keys of users remembered for a server address, by lowercased name;
+/// one `address user key` triple per line.
+pub fn load_known_keys(addr: &str) -> HashMap<String, String> {
+	let keys = match config_dir().map(|dir| fs::read_to_string(dir.join(KNOWN_KEYS_FILE))) {
+		Some(Ok(keys)) => keys,
+		_ => return HashMap::new(),
+	};
+	keys.lines()
+		.filter_map(|line| {
+			let mut fields = line.split(' ');
+			match (fields.next(), fields.next(), fields.next()) {
+				(Some(host), Some(user), Some(key)) if host == addr => Some((user.to_string(), key.trim().to_string())),
+				_ => None,
+			}
+		})
+		.collect()
+}
+
+/// Remembers the encryption key of a user on a server address, replacing any earlier one.
+pub fn save_known_key(addr: &str, username: &str, key: &str) -> io::Result<()> {
+	let dir = config_dir().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+	fs::create_dir_all(&dir)?;
+	let path = dir.join(KNOWN_KEYS_FILE);
+	let keys = match fs::read_to_string(&path) {
+		Ok(keys) => keys,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+		Err(err) => return Err(err),
+	};
+	let mut lines: Vec<&str> = keys.lines().filter(|line| {
+		let mut fields = line.split(' ');
+		(fields.next(), fields.next()) != (Some(addr), Some(username))
+	}).collect();
+	let entry = format!("{} {} {}", addr, username, key);
+	lines.push(&entry);
+	fs::write(path, lines.join("\n") + "\n")
+}
+
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+#[allow(dead_code)]
 pub struct Config {
 	pub client: Client,
-	pub env: Env
+	pub env: Env,
+	pub tls: Tls,
+	pub server: Server
+}
+
+impl Config {
+	/// Reads a config file, explaining what is wrong with it if it can't be used.
+	pub fn load(path: &Path) -> Result<Config, String> {
+		let raw = fs::read_to_string(path).map_err(|err| format!("Can't read config file {}: {}", path.display(), err))?;
+		let config: Config = toml::from_str(&raw).map_err(|err| format!("Invalid config file {}: {}", path.display(), err))?;
+		config.server.validate().map_err(|err| format!("Invalid config file {}: {}", path.display(), err))?;
+		Ok(config)
+	}
+}
+
+/// `[server]`: settings only the server reads. Command line flags override them.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+	/// Hosts or IP addresses to listen on, each with an optional `:port`.
+	/// `::` takes IPv4 connections too unless `0.0.0.0` is also listed.
+	pub bind: Vec<String>,
+	/// Port of the `bind` entries that don't name one
+	pub port: u16,
+	/// Connections beyond this many are turned away; no limit if missing
+	pub max_clients: Option<usize>,
+	/// Message of the day, shown to every client once it is welcomed
+	pub motd: Option<String>,
+	pub history_dir: Option<PathBuf>,
+	/// Messages of a room's history sent to a client when it joins
+	pub history_backlog: usize,
+	/// Bytes a room's log file may grow to before a new one is started
+	pub history_max_file_size: u64,
+	pub user_db: Option<PathBuf>,
+	pub moderation_db: Option<PathBuf>,
+	/// Whether clients must log in or register before joining any room
+	pub require_auth: bool,
+	/// Seconds to wait for clients to leave when shutting down
+	pub shutdown_timeout: u64,
+	/// Unix socket `svchat admin` manages the server through; none if unset
+	pub control_socket: Option<PathBuf>,
+	/// Address to serve Prometheus metrics over HTTP on; none if unset
+	pub metrics: Option<SocketAddr>,
+	pub log_level: crate::log::Level,
+	pub rate_limit: crate::ratelimit::RateLimitConfig
 }
 
-impl Default for Config {
+impl Default for Server {
 	fn default() -> Self {
 		Self {
-			client: Client::default(),
-			env: Env::default()
+			bind: vec!["127.0.0.1".to_string()],
+			port: 6000,
+			max_clients: None,
+			motd: None,
+			history_dir: None,
+			history_backlog: crate::history::DEFAULT_BACKLOG,
+			history_max_file_size: crate::history::DEFAULT_MAX_FILE_SIZE,
+			user_db: None,
+			moderation_db: None,
+			require_auth: false,
+			shutdown_timeout: 5,
+			control_socket: None,
+			metrics: None,
+			log_level: crate::log::Level::default(),
+			rate_limit: crate::ratelimit::RateLimitConfig::default()
 		}
 	}
 }
 
+impl Server {
+	/// Catches values that parse but make no sense.
+	pub fn validate(&self) -> Result<(), String> {
+		if self.bind.is_empty() || self.bind.iter().any(|addr| addr.is_empty()) {
+			return Err(String::from("server.bind needs at least one address, and no empty ones"));
+		}
+		if self.max_clients == Some(0) {
+			return Err(String::from("server.max_clients must be at least 1, leave it out for no limit"));
+		}
+		if self.history_max_file_size == 0 {
+			return Err(String::from("server.history_max_file_size must be at least 1"));
+		}
+		self.rate_limit.validate()
+	}
+}
+
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct Client {
 	pub username: String,
 	pub custom_color: String,
@@ -32,7 +211,22 @@ impl Default for Client {
 	}
 }
 
+/// `[tls]`: certificate and key for the server, trust settings for the client.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Tls {
+	/// Connect to the server over TLS
+	pub enabled: bool,
+	/// PEM file of the CA that signed the server's certificate
+	pub ca: Option<String>,
+	/// Pinned fingerprint of the server's public key, as printed by the server
+	pub fingerprint: Option<String>,
+	pub cert: Option<String>,
+	pub key: Option<String>
+}
+
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct Env {
 	pub local_color: String,
 	pub remote_color: String,
@@ -0,0 +1,96 @@
+use std::{io::{self, IsTerminal}, mem, thread};
+
+use crossterm::{event::{self, Event, KeyCode, KeyModifiers}, terminal::{disable_raw_mode, enable_raw_mode}};
+
+use crate::admin::{self, Command};
+use crate::moderation;
+use crate::server::{Control, Handle};
+
+const PROMPT: &str = "> ";
+
+const SHUTDOWN: &str = "\
+shutdown [back in] [reason]          Stop the server, e.g. shutdown 10m upgrading";
+
+/// The admin console on the server's terminal, closed again on drop.
+pub struct Console;
+
+impl Console {
+	/// Opens the console if the server runs in a terminal.
+	pub fn open(handle: Handle) -> Option<Console> {
+		if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+			return None;
+		}
+		if let Err(err) = enable_raw_mode() {
+			log!(Warn, "Can't open the admin console: {}", err);
+			return None;
+		}
+		crate::log::set_prompt(Some(PROMPT.to_string()));
+		thread::spawn(move || run(&handle));
+		Some(Console)
+	}
+}
+
+impl Drop for Console {
+	fn drop(&mut self) {
+		crate::log::set_prompt(None);
+		disable_raw_mode().ok();
+	}
+}
+
+/// Reads keys until the terminal goes away, running each line entered.
+fn run(handle: &Handle) {
+	let mut line = String::new();
+	loop {
+		let key = match event::read() {
+			Ok(Event::Key(key)) => key,
+			Ok(_) => continue,
+			Err(err) => {
+				log!(Warn, "Admin console closed: {}", err);
+				return;
+			}
+		};
+		match key.code {
+			// Raw mode keeps ^C from raising SIGINT, so stand in for it
+			KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+				handle.send(Control::Shutdown { reason: None, restart_in: None });
+			}
+			KeyCode::Char(c) => line.push(c),
+			KeyCode::Backspace => {
+				line.pop();
+			}
+			KeyCode::Enter => {
+				let entered = mem::take(&mut line);
+				crate::log::set_prompt(Some(PROMPT.to_string()));
+				print(&format!("{}{}", PROMPT, entered));
+				execute(&entered, handle);
+			}
+			_ => (),
+		}
+		crate::log::set_prompt(Some(format!("{}{}", PROMPT, line)));
+	}
+}
+
+fn execute(line: &str, handle: &Handle) {
+	let words: Vec<&str> = line.split_whitespace().collect();
+	match words.as_slice() {
+		[] => (),
+		["help"] => admin::COMMANDS.lines().chain(SHUTDOWN.lines()).for_each(print),
+		["shutdown", args @ ..] => {
+			let (restart_in, reason) = moderation::duration_and_text(args);
+			handle.send(Control::Shutdown { reason, restart_in });
+		}
+		words => match Command::parse(words) {
+			// Nothing comes back once the event loop has stopped
+			Some(command) => {
+				if let Some(reply) = handle.ask(command) {
+					reply.lines().iter().for_each(|line| print(line));
+				}
+			}
+			None => print("Unknown command, try help"),
+		},
+	}
+}
+
+fn print(line: &str) {
+	crate::log::print(format_args!("{}", line));
+}
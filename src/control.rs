@@ -0,0 +1,119 @@
+use std::{
+	fs,
+	io::{self, BufRead, BufReader, ErrorKind, Write},
+	os::unix::{fs::{DirBuilderExt, FileTypeExt, PermissionsExt}, net::{UnixListener, UnixStream}},
+	path::{Path, PathBuf},
+	thread,
+};
+
+use crate::admin::{Command, Reply};
+use crate::server::Handle;
+
+/// The control socket, removed again on drop.
+///
+/// Each line a client writes is a JSON `Command`, answered by a line with a JSON `Reply`.
+pub struct ControlSocket {
+	path: PathBuf,
+}
+
+impl ControlSocket {
+	pub fn open(path: &Path, handle: Handle) -> io::Result<ControlSocket> {
+		// A socket left behind by a server that died can be replaced, a live one can't,
+		// and whatever else is at the path is never touched
+		match fs::symlink_metadata(path) {
+			Ok(metadata) if !metadata.file_type().is_socket() => {
+				return Err(io::Error::new(ErrorKind::AlreadyExists, format!("{} exists and isn't a socket", path.display())));
+			}
+			Ok(_) if UnixStream::connect(path).is_ok() => {
+				return Err(io::Error::new(ErrorKind::AddrInUse, format!("Another server is using the control socket {}", path.display())));
+			}
+			Ok(_) => fs::remove_file(path)?,
+			Err(err) if err.kind() == ErrorKind::NotFound => (),
+			Err(err) => return Err(err),
+		}
+		let dir = match path.parent() {
+			Some(dir) if !dir.as_os_str().is_empty() => dir,
+			_ => Path::new("."),
+		};
+		fs::create_dir_all(dir)?;
+		let listener = bind(dir, path)
+			.map_err(|err| io::Error::new(err.kind(), format!("Can't create the control socket {}: {}", path.display(), err)))?;
+		log!(Info, "Control socket at {}", path.display());
+
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				match stream {
+					Ok(stream) => {
+						let handle = handle.clone();
+						thread::spawn(move || serve(stream, &handle));
+					}
+					Err(err) => log!(Warn, "Failed to accept on the control socket: {}", err),
+				}
+			}
+		});
+		Ok(ControlSocket { path: path.to_path_buf() })
+	}
+}
+
+/// Binds the socket in a directory only we can enter, so it is never reachable
+/// before its permissions are tightened, then moves it to `path`.
+fn bind(dir: &Path, path: &Path) -> io::Result<UnixListener> {
+	let private = dir.join(format!(".svchat-control-{}", std::process::id()));
+	fs::DirBuilder::new().mode(0o700).create(&private)?;
+	let staged = private.join("control.sock");
+	let listener = UnixListener::bind(&staged).and_then(|listener| {
+		// Whoever can connect can run the server
+		fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+		fs::rename(&staged, path)?;
+		Ok(listener)
+	});
+	fs::remove_file(&staged).ok();
+	fs::remove_dir(&private).ok();
+	listener
+}
+
+impl Drop for ControlSocket {
+	fn drop(&mut self) {
+		fs::remove_file(&self.path).ok();
+	}
+}
+
+/// Answers the commands of one control connection until it closes.
+fn serve(stream: UnixStream, handle: &Handle) {
+	let mut writer = match stream.try_clone() {
+		Ok(writer) => writer,
+		Err(_) => return,
+	};
+	for line in BufReader::new(stream).lines().map_while(Result::ok) {
+		if line.trim().is_empty() {
+			continue;
+		}
+		let reply = match serde_json::from_str::<Command>(&line) {
+			Ok(command) => {
+				log!(Debug, "Control socket: {}", line);
+				handle.ask(command).unwrap_or_else(|| Reply::Error { reason: String::from("The server is shutting down") })
+			}
+			Err(err) => Reply::Error { reason: format!("Invalid command: {}", err) },
+		};
+		let mut outbound = serde_json::to_vec(&reply).unwrap();
+		outbound.push(b'\n');
+		if writer.write_all(&outbound).is_err() {
+			return;
+		}
+	}
+}
+
+/// Runs one command on the server listening on the control socket at `path`.
+pub fn request(path: &Path, command: &Command) -> io::Result<Reply> {
+	let mut stream = UnixStream::connect(path)?;
+	let mut outbound = serde_json::to_vec(command)?;
+	outbound.push(b'\n');
+	stream.write_all(&outbound)?;
+
+	let mut line = String::new();
+	BufReader::new(stream).read_line(&mut line)?;
+	if line.is_empty() {
+		return Err(io::Error::new(ErrorKind::UnexpectedEof, "The server closed the connection"));
+	}
+	Ok(serde_json::from_str(&line)?)
+}
@@ -0,0 +1,156 @@
+use std::{convert::TryInto, fs, io::{self, Write}};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng}, ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::structs::Sealed;
+
+const IDENTITY_FILE: &str = "identity";
+/// Binds derived keys to this use, so the same keypair could safely serve another one.
+const KEY_INFO: &[u8] = b"svchat direct message v1";
+
+/// The client's long-term x25519 keypair, used for every direct message.
+///
+/// Both sides of a conversation derive the same key from their own secret and
+/// the other's public key, so there is no forward secrecy: a leaked identity
+/// file exposes every message sealed with it.
+pub struct Identity {
+	secret: StaticSecret,
+}
+
+impl Identity {
+	pub fn generate() -> Identity {
+		Identity { secret: StaticSecret::random_from_rng(OsRng) }
+	}
+
+	/// Loads the keypair from the config dir, creating it on first use.
+	pub fn load_or_create() -> io::Result<Identity> {
+		let dir = crate::config::config_dir().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+		let path = dir.join(IDENTITY_FILE);
+		match fs::read_to_string(&path) {
+			Ok(content) => {
+				let bytes: [u8; 32] = STANDARD.decode(content.trim()).ok()
+					.and_then(|bytes| bytes.try_into().ok())
+					.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a valid identity key", path.display())))?;
+				Ok(Identity { secret: StaticSecret::from(bytes) })
+			}
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+				let identity = Identity::generate();
+				fs::create_dir_all(&dir)?;
+				let mut options = fs::OpenOptions::new();
+				options.write(true).create_new(true);
+				// Never readable by anyone else, not even before the key is in it
+				#[cfg(unix)]
+				{
+					use std::os::unix::fs::OpenOptionsExt;
+					options.mode(0o600);
+				}
+				options.open(&path)?.write_all(STANDARD.encode(identity.secret.to_bytes()).as_bytes())?;
+				Ok(identity)
+			}
+			Err(err) => Err(err),
+		}
+	}
+
+	/// The public key as published to the server, base64.
+	pub fn public_key(&self) -> String {
+		STANDARD.encode(PublicKey::from(&self.secret).as_bytes())
+	}
+
+	/// Cipher for the conversation with the owner of `peer_key`.
+	fn cipher(&self, peer_key: &str) -> Result<ChaCha20Poly1305, String> {
+		let peer: [u8; 32] = STANDARD.decode(peer_key).ok()
+			.and_then(|bytes| bytes.try_into().ok())
+			.ok_or_else(|| String::from("invalid public key"))?;
+		let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+		let mut key = [0; 32];
+		Hkdf::<Sha256>::new(None, shared.as_bytes())
+			.expand(KEY_INFO, &mut key)
+			.map_err(|_| String::from("key derivation failed"))?;
+		Ok(ChaCha20Poly1305::new(&key.into()))
+	}
+
+	pub fn seal(&self, peer_key: &str, plaintext: &str) -> Result<Sealed, String> {
+		let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+		let ciphertext = self.cipher(peer_key)?
+			.encrypt(&nonce, plaintext.as_bytes())
+			.map_err(|_| String::from("encryption failed"))?;
+		Ok(Sealed { nonce: STANDARD.encode(nonce), ciphertext: STANDARD.encode(ciphertext) })
+	}
+
+	pub fn open(&self, peer_key: &str, sealed: &Sealed) -> Result<String, String> {
+		let nonce = STANDARD.decode(&sealed.nonce).ok()
+			.filter(|nonce| nonce.len() == 12)
+			.ok_or_else(|| String::from("invalid nonce"))?;
+		let ciphertext = STANDARD.decode(&sealed.ciphertext).map_err(|_| String::from("invalid ciphertext"))?;
+		let plaintext = self.cipher(peer_key)?
+			.decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+			.map_err(|_| String::from("message was tampered with or sealed for another key"))?;
+		String::from_utf8(plaintext).map_err(|_| String::from("message is not text"))
+	}
+}
+
+/// Thirty digits both users see for the same pair of keys, in any order, to
+/// compare over another channel.
+pub fn safety_number(a: &str, b: &str) -> String {
+	let (first, second) = if a <= b { (a, b) } else { (b, a) };
+	let digest = Sha256::new().chain_update(first).chain_update(b"|").chain_update(second).finalize();
+	digest.chunks(5)
+		.take(6)
+		.map(|chunk| {
+			let n = chunk.iter().fold(0u64, |n, byte| n << 8 | *byte as u64);
+			format!("{:05}", n % 100_000)
+		})
+		.collect::<Vec<String>>()
+		.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn opens_what_the_peer_sealed() {
+		let alice = Identity::generate();
+		let bob = Identity::generate();
+
+		let sealed = alice.seal(&bob.public_key(), "hello bob").unwrap();
+		assert_eq!(bob.open(&alice.public_key(), &sealed).unwrap(), "hello bob");
+	}
+
+	#[test]
+	fn rejects_tampered_messages() {
+		let alice = Identity::generate();
+		let bob = Identity::generate();
+		let mut sealed = alice.seal(&bob.public_key(), "hello bob").unwrap();
+
+		let mut ciphertext = STANDARD.decode(&sealed.ciphertext).unwrap();
+		ciphertext[0] ^= 1;
+		sealed.ciphertext = STANDARD.encode(ciphertext);
+		assert!(bob.open(&alice.public_key(), &sealed).is_err());
+	}
+
+	#[test]
+	fn rejects_messages_sealed_for_another_key() {
+		let alice = Identity::generate();
+		let bob = Identity::generate();
+		let mallory = Identity::generate();
+		let sealed = alice.seal(&bob.public_key(), "hello bob").unwrap();
+
+		assert!(mallory.open(&alice.public_key(), &sealed).is_err());
+		// Nor does bob read it as coming from someone else
+		assert!(bob.open(&mallory.public_key(), &sealed).is_err());
+	}
+
+	#[test]
+	fn safety_number_ignores_order() {
+		let a = Identity::generate().public_key();
+		let b = Identity::generate().public_key();
+
+		assert_eq!(safety_number(&a, &b), safety_number(&b, &a));
+		assert_ne!(safety_number(&a, &b), safety_number(&a, &a));
+	}
+}
@@ -0,0 +1,274 @@
+use std::{convert::TryInto, error::Error, fmt, io::{self, ErrorKind, Read, Write}};
+
+/// Size of the big-endian `u32` length prefix in front of every frame.
+pub const HEADER_SIZE: usize = 4;
+/// Largest payload accepted or produced unless a codec is configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+const READ_CHUNK: usize = 4096;
+
+#[derive(Debug)]
+pub enum FrameError {
+	/// The underlying stream failed.
+	Io(io::Error),
+	/// A frame declared or carried more bytes than the codec allows.
+	TooLarge { size: usize, max: usize },
+	/// The peer closed the stream.
+	Closed,
+}
+
+impl fmt::Display for FrameError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			FrameError::Io(err) => write!(f, "i/o error: {}", err),
+			FrameError::TooLarge { size, max } => write!(f, "frame of {} bytes exceeds the {} byte limit", size, max),
+			FrameError::Closed => write!(f, "connection closed"),
+		}
+	}
+}
+
+impl Error for FrameError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			FrameError::Io(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for FrameError {
+	fn from(err: io::Error) -> Self {
+		match err.kind() {
+			ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => FrameError::Closed,
+			_ => FrameError::Io(err),
+		}
+	}
+}
+
+/// Splits a byte stream into length-prefixed frames.
+///
+/// Bytes are buffered between calls, so a frame that arrives in pieces on a
+/// non-blocking socket is handed out once its last byte has been read.
+pub struct FrameDecoder {
+	buf: Vec<u8>,
+	max_frame_size: usize,
+}
+
+impl FrameDecoder {
+	pub fn new(max_frame_size: usize) -> FrameDecoder {
+		FrameDecoder {
+			buf: Vec::new(),
+			max_frame_size,
+		}
+	}
+
+	/// Reads from `reader` until a whole frame is buffered and returns its payload.
+	///
+	/// Returns `Ok(None)` if the reader would block before a frame is complete.
+	pub fn read_frame<R: Read>(&mut self, reader: &mut R) -> Result<Option<Vec<u8>>, FrameError> {
+		loop {
+			if let Some(frame) = self.next_frame()? {
+				return Ok(Some(frame));
+			}
+
+			let mut chunk = [0; READ_CHUNK];
+			match reader.read(&mut chunk) {
+				Ok(0) => return Err(FrameError::Closed),
+				Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+				Err(err) => return Err(err.into()),
+			}
+		}
+	}
+
+	/// Takes the next complete frame out of the internal buffer without reading.
+	pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+		if self.buf.len() < HEADER_SIZE {
+			return Ok(None);
+		}
+
+		let size = u32::from_be_bytes(self.buf[..HEADER_SIZE].try_into().unwrap()) as usize;
+		if size > self.max_frame_size {
+			return Err(FrameError::TooLarge { size, max: self.max_frame_size });
+		}
+		if self.buf.len() < HEADER_SIZE + size {
+			return Ok(None);
+		}
+
+		let frame = self.buf[HEADER_SIZE..HEADER_SIZE + size].to_vec();
+		self.buf.drain(..HEADER_SIZE + size);
+		Ok(Some(frame))
+	}
+}
+
+/// Queues payloads as length-prefixed frames and writes them out.
+///
+/// Writes stop at `WouldBlock` and resume on the next flush, so the encoder
+/// can sit in front of a non-blocking socket.
+pub struct FrameEncoder {
+	buf: Vec<u8>,
+	max_frame_size: usize,
+}
+
+impl FrameEncoder {
+	pub fn new(max_frame_size: usize) -> FrameEncoder {
+		FrameEncoder {
+			buf: Vec::new(),
+			max_frame_size,
+		}
+	}
+
+	/// Appends `payload` to the outgoing queue.
+	pub fn push(&mut self, payload: &[u8]) -> Result<(), FrameError> {
+		if payload.len() > self.max_frame_size {
+			return Err(FrameError::TooLarge { size: payload.len(), max: self.max_frame_size });
+		}
+
+		self.buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+		self.buf.extend_from_slice(payload);
+		Ok(())
+	}
+
+	/// Bytes queued and not yet written.
+	pub fn queued(&self) -> usize {
+		self.buf.len()
+	}
+
+	/// Writes as much of the queue as `writer` accepts.
+	///
+	/// Returns `Ok(true)` once everything queued has been written.
+	pub fn flush_to<W: Write>(&mut self, writer: &mut W) -> Result<bool, FrameError> {
+		while !self.buf.is_empty() {
+			match writer.write(&self.buf) {
+				Ok(0) => return Err(FrameError::Closed),
+				Ok(n) => {
+					self.buf.drain(..n);
+				}
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+				Err(err) => return Err(err.into()),
+			}
+		}
+		// A TLS stream may still hold records back until the socket takes them
+		match writer.flush() {
+			Ok(()) => Ok(true),
+			Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+			Err(err) => Err(err.into()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::VecDeque;
+
+	use super::*;
+
+	/// What a `Script` hands out on each read.
+	enum Step {
+		Data(Vec<u8>),
+		WouldBlock,
+	}
+
+	/// A reader that plays back its steps one read at a time, then reports end of file.
+	struct Script(VecDeque<Step>);
+
+	impl Script {
+		fn new(steps: Vec<Step>) -> Script {
+			Script(steps.into())
+		}
+	}
+
+	impl Read for Script {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			match self.0.pop_front() {
+				Some(Step::Data(mut data)) => {
+					let n = data.len().min(buf.len());
+					buf[..n].copy_from_slice(&data[..n]);
+					// Whatever didn't fit comes on the next read
+					if n < data.len() {
+						self.0.push_front(Step::Data(data.split_off(n)));
+					}
+					Ok(n)
+				}
+				Some(Step::WouldBlock) => Err(ErrorKind::WouldBlock.into()),
+				None => Ok(0),
+			}
+		}
+	}
+
+	fn framed(payload: &[u8]) -> Vec<u8> {
+		let mut encoder = FrameEncoder::new(DEFAULT_MAX_FRAME_SIZE);
+		encoder.push(payload).unwrap();
+		let mut out = Vec::new();
+		encoder.flush_to(&mut out).unwrap();
+		out
+	}
+
+	#[test]
+	fn joins_a_frame_split_across_reads() {
+		let bytes = framed(b"hello there");
+		let steps = bytes.iter().map(|&byte| Step::Data(vec![byte])).collect();
+		let mut reader = Script::new(steps);
+		let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE);
+
+		assert_eq!(decoder.read_frame(&mut reader).unwrap().unwrap(), b"hello there");
+	}
+
+	#[test]
+	fn resumes_after_would_block() {
+		let bytes = framed(b"first");
+		let mut second = framed(b"second");
+		// The second frame's header arrives with the end of the first
+		let mut tail = bytes[3..].to_vec();
+		tail.extend(second.drain(..2));
+		let mut reader = Script::new(vec![
+			Step::Data(bytes[..3].to_vec()),
+			Step::WouldBlock,
+			Step::Data(tail),
+			Step::WouldBlock,
+			Step::Data(second),
+			Step::WouldBlock,
+		]);
+		let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE);
+
+		assert_eq!(decoder.read_frame(&mut reader).unwrap(), None);
+		assert_eq!(decoder.read_frame(&mut reader).unwrap().unwrap(), b"first");
+		assert_eq!(decoder.read_frame(&mut reader).unwrap(), None);
+		assert_eq!(decoder.read_frame(&mut reader).unwrap().unwrap(), b"second");
+		assert_eq!(decoder.read_frame(&mut reader).unwrap(), None);
+	}
+
+	#[test]
+	fn rejects_an_oversize_header_before_its_payload() {
+		// Only the length prefix ever arrives
+		let mut reader = Script::new(vec![Step::Data(u32::MAX.to_be_bytes().to_vec()), Step::WouldBlock]);
+		let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE);
+
+		match decoder.read_frame(&mut reader) {
+			Err(FrameError::TooLarge { size, max }) => {
+				assert_eq!(size, u32::MAX as usize);
+				assert_eq!(max, DEFAULT_MAX_FRAME_SIZE);
+			}
+			other => panic!("expected TooLarge, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn reports_closed_mid_frame() {
+		let bytes = framed(b"cut short");
+		let mut reader = Script::new(vec![Step::Data(bytes[..8].to_vec())]);
+		let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE);
+
+		assert!(matches!(decoder.read_frame(&mut reader), Err(FrameError::Closed)));
+	}
+
+	#[test]
+	fn encoder_refuses_oversize_payloads() {
+		let mut encoder = FrameEncoder::new(8);
+
+		assert!(matches!(encoder.push(&[0; 9]), Err(FrameError::TooLarge { size: 9, max: 8 })));
+		assert!(encoder.push(&[0; 8]).is_ok());
+	}
+}
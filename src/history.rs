@@ -0,0 +1,223 @@
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, sync::mpsc, thread};
+
+use chrono::Utc;
+use mio::Token;
+
+use crate::server::{Control, Handle};
+use crate::structs::Msg;
+
+/// Messages sent to a client when it joins a room.
+pub const DEFAULT_BACKLOG: usize = 50;
+/// Size at which a room's log is rotated to a new file.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Clone)]
+pub struct HistoryConfig {
+	pub dir: PathBuf,
+	pub max_file_size: u64,
+	pub backlog: usize,
+}
+
+impl Default for HistoryConfig {
+	fn default() -> Self {
+		Self {
+			dir: crate::config::data_dir().unwrap_or_default().join("history"),
+			max_file_size: DEFAULT_MAX_FILE_SIZE,
+			backlog: DEFAULT_BACKLOG,
+		}
+	}
+}
+
+/// Log file a room is currently appending to.
+struct OpenLog {
+	path: PathBuf,
+	file: File,
+	size: u64,
+}
+
+/// Append-only message logs, one directory per room.
+///
+/// Each room gets a file per day, `YYYY-MM-DD-NNN.jsonl`, holding one JSON
+/// `Msg` per line. `NNN` is bumped whenever a file outgrows `max_file_size`,
+/// so sorting the names sorts the files oldest to newest.
+pub struct History {
+	config: HistoryConfig,
+	open: HashMap<String, OpenLog>,
+}
+
+impl History {
+	pub fn new(config: HistoryConfig) -> io::Result<History> {
+		fs::create_dir_all(&config.dir)?;
+		Ok(History {
+			config,
+			open: HashMap::new(),
+		})
+	}
+
+	pub fn backlog_size(&self) -> usize {
+		self.config.backlog
+	}
+
+	/// Rooms that have a log on disk.
+	pub fn rooms(&self) -> io::Result<Vec<String>> {
+		let mut rooms = Vec::new();
+		for entry in fs::read_dir(&self.config.dir)? {
+			let entry = entry?;
+			if entry.file_type()?.is_dir() {
+				if let Some(name) = entry.file_name().to_str() {
+					rooms.push(name.to_string());
+				}
+			}
+		}
+		Ok(rooms)
+	}
+
+	pub fn append(&mut self, msg: &Msg) -> io::Result<()> {
+		let mut line = serde_json::to_vec(msg)?;
+		line.push(b'\n');
+
+		let prefix = Utc::now().format("%Y-%m-%d").to_string();
+		let needs_new_file = match self.open.get(&msg.room) {
+			Some(log) => {
+				!file_name(&log.path).starts_with(&prefix)
+					|| log.size + line.len() as u64 > self.config.max_file_size
+			}
+			None => true,
+		};
+		if needs_new_file {
+			let log = self.open_log(&msg.room, &prefix, line.len() as u64)?;
+			self.open.insert(msg.room.clone(), log);
+		}
+
+		let log = self.open.get_mut(&msg.room).unwrap();
+		log.file.write_all(&line)?;
+		log.size += line.len() as u64;
+		Ok(())
+	}
+
+	/// Forces everything appended so far onto the disk.
+	pub fn sync(&self) -> io::Result<()> {
+		for log in self.open.values() {
+			log.file.sync_all()?;
+		}
+		Ok(())
+	}
+
+	/// The newest `count` messages of a room, oldest first.
+	pub fn last(&self, room: &str, count: usize) -> io::Result<Vec<Msg>> {
+		self.before(room, u64::MAX, count)
+	}
+
+	/// Up to `count` messages of a room with a sequence number below `seq`, oldest first.
+	pub fn before(&self, room: &str, seq: u64, count: usize) -> io::Result<Vec<Msg>> {
+		scan_back(&self.config.dir, room, count, |m| m.seq < seq)
+	}
+
+	/// Starts the thread searches run on; results go back to the event loop through `handle`.
+	pub fn spawn_searcher(&self, handle: Handle) -> mpsc::Sender<Search> {
+		let dir = self.config.dir.clone();
+		let (jobs_tx, jobs) = mpsc::channel::<Search>();
+		thread::spawn(move || {
+			for search in jobs {
+				let query = search.query.to_lowercase();
+				let results = scan_back(&dir, &search.room, search.limit, |m| m.content.to_lowercase().contains(&query));
+				handle.send(Control::Searched(search, results));
+			}
+		});
+		jobs_tx
+	}
+
+	/// Sequence number of the newest logged message of a room, 0 if it has none.
+	pub fn last_seq(&self, room: &str) -> io::Result<u64> {
+		Ok(self.last(room, 1)?.first().map_or(0, |m| m.seq))
+	}
+
+	fn room_dir(&self, room: &str) -> PathBuf {
+		self.config.dir.join(room)
+	}
+
+	/// Opens the newest of today's files that still has room for `incoming` bytes.
+	fn open_log(&self, room: &str, prefix: &str, incoming: u64) -> io::Result<OpenLog> {
+		let dir = self.room_dir(room);
+		fs::create_dir_all(&dir)?;
+
+		let mut index = 0;
+		for path in files(&self.config.dir, room)? {
+			if let Some((date, n)) = log_key(&path) {
+				if date == prefix {
+					index = n;
+				}
+			}
+		}
+
+		loop {
+			let path = dir.join(format!("{}-{:03}.jsonl", prefix, index));
+			let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+			if size == 0 || size + incoming <= self.config.max_file_size {
+				let file = OpenOptions::new().create(true).append(true).open(&path)?;
+				return Ok(OpenLog { path, file, size });
+			}
+			index += 1;
+		}
+	}
+}
+
+fn file_name(path: &Path) -> String {
+	path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string()
+}
+
+/// A search through a room's whole log, which runs off the event loop as it may read every file.
+pub struct Search {
+	pub token: Token,
+	pub room: String,
+	pub query: String,
+	pub limit: usize,
+}
+
+/// Log files of a room, oldest first.
+fn files(dir: &Path, room: &str) -> io::Result<Vec<PathBuf>> {
+	let mut files = Vec::new();
+	match fs::read_dir(dir.join(room)) {
+		Ok(entries) => {
+			for entry in entries {
+				let path = entry?.path();
+				if path.extension().is_some_and(|ext| ext == "jsonl") && log_key(&path).is_some() {
+					files.push(path);
+				}
+			}
+		}
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+		Err(err) => return Err(err),
+	}
+	// By number, as the index outgrows its padding after 999
+	files.sort_by_cached_key(|path| log_key(path));
+	Ok(files)
+}
+
+/// Walks a room's logs from the newest message backwards, collecting up to
+/// `count` messages that `keep` accepts, and returns them oldest first.
+fn scan_back<F: Fn(&Msg) -> bool>(dir: &Path, room: &str, count: usize, keep: F) -> io::Result<Vec<Msg>> {
+	let mut newest_first = Vec::new();
+	for path in files(dir, room)?.iter().rev() {
+		let msgs = read_log(path)?;
+		newest_first.extend(msgs.into_iter().rev().filter(|m| keep(m)));
+		if newest_first.len() >= count {
+			break;
+		}
+	}
+	newest_first.truncate(count);
+	newest_first.reverse();
+	Ok(newest_first)
+}
+
+/// The date and index a log file is named after, e.g. `2024-01-31` and 7 for `2024-01-31-007.jsonl`.
+fn log_key(path: &Path) -> Option<(String, u32)> {
+	let (date, index) = path.file_stem()?.to_str()?.rsplit_once('-')?;
+	Some((date.to_string(), index.parse().ok()?))
+}
+
+fn read_log(path: &Path) -> io::Result<Vec<Msg>> {
+	let content = fs::read_to_string(path)?;
+	// A line cut short by a crash is skipped rather than failing the whole log
+	Ok(content.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+}
@@ -0,0 +1,79 @@
+use std::{fmt, io::{self, Write}, str::FromStr, sync::{atomic::{AtomicU8, Ordering}, Mutex, PoisonError}};
+
+use crossterm::{queue, terminal::{Clear, ClearType}};
+use serde::Deserialize;
+
+/// How much the server prints, from least to most.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+	Error,
+	Warn,
+	#[default]
+	Info,
+	/// Also prints every command clients send.
+	Debug,
+}
+
+impl FromStr for Level {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"error" => Ok(Level::Error),
+			"warn" => Ok(Level::Warn),
+			"info" => Ok(Level::Info),
+			"debug" => Ok(Level::Debug),
+			_ => Err(format!("Unknown log level {}, expected error, warn, info or debug", s)),
+		}
+	}
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+	LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+	level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// The admin console's prompt and what has been typed at it, kept below everything printed.
+static PROMPT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Prints a line, above the admin console's prompt if it is open.
+pub fn print(args: fmt::Arguments) {
+	let prompt = PROMPT.lock().unwrap_or_else(PoisonError::into_inner);
+	let mut out = io::stdout().lock();
+	match prompt.as_deref() {
+		Some(prompt) => {
+			queue!(out, Clear(ClearType::CurrentLine)).ok();
+			// The console's raw mode needs explicit carriage returns
+			write!(out, "\r{}\r\n{}", args, prompt).ok();
+		}
+		None => {
+			writeln!(out, "{}", args).ok();
+		}
+	}
+	out.flush().ok();
+}
+
+/// Shows `prompt` on the last line until it is replaced, or removes it.
+pub fn set_prompt(prompt: Option<String>) {
+	let mut current = PROMPT.lock().unwrap_or_else(PoisonError::into_inner);
+	let mut out = io::stdout().lock();
+	queue!(out, Clear(ClearType::CurrentLine)).ok();
+	write!(out, "\r{}", prompt.as_deref().unwrap_or("")).ok();
+	out.flush().ok();
+	*current = prompt;
+}
+
+/// `println!` that only prints at or below the configured level, e.g. `log!(Warn, "...")`.
+macro_rules! log {
+	($level:ident, $($arg:tt)*) => {
+		if $crate::log::enabled($crate::log::Level::$level) {
+			$crate::log::print(format_args!($($arg)*));
+		}
+	};
+}
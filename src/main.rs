@@ -1,13 +1,28 @@
+#[macro_use]
+mod log;
 mod server;
 mod client;
 mod structs;
 mod config;
+mod frame;
+mod protocol;
+mod history;
+mod accounts;
+mod tls;
+mod e2e;
+mod ratelimit;
+mod moderation;
+mod metrics;
+mod admin;
+mod console;
+#[cfg(unix)]
+mod control;
 
 use std::{
-    process::exit, fs,
+    net::IpAddr, path::{Path, PathBuf}, process::exit,
 };
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use gethostname::gethostname;
 
 fn main() -> std::io::Result<()> {
@@ -26,14 +41,16 @@ fn main() -> std::io::Result<()> {
             Arg::with_name("address")
                 .short("a")
                 .long("address")
-                .help("Sets address to connect to (defaults to localhost)")
-                .takes_value(true),
+                .help("Sets address to connect to, or to listen on in server mode, where it can be given several times (defaults to 127.0.0.1)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
         )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
-                .help("Sets port to connect to (defaults to 6000)")
+                .help("Sets port to connect to, or to listen on in server mode (defaults to 6000)")
                 .takes_value(true),
         )
         .arg(
@@ -43,6 +60,98 @@ fn main() -> std::io::Result<()> {
                 .help("Username to be identified with (defaults to hostname of machine)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("room")
+                .short("r")
+                .long("room")
+                .help("Room to join on connect (defaults to the last joined room)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("history-dir")
+                .long("history-dir")
+                .help("Directory the server logs room messages to (defaults to ~/.local/share/svchat/history)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("user-db")
+                .long("user-db")
+                .help("File the server keeps registered accounts in (defaults to ~/.local/share/svchat/users.json)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("moderation-db")
+                .long("moderation-db")
+                .help("File the server keeps room roles, bans and mutes in (defaults to ~/.local/share/svchat/moderation.json)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("require-auth")
+                .long("require-auth")
+                .help("Makes clients log in or register before joining any room (server mode)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("max-clients")
+                .long("max-clients")
+                .help("Turns away connections beyond this many (server mode)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("control-socket")
+                .long("control-socket")
+                .help("Unix socket to manage the server through with svchat admin (server mode)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .help("Serves Prometheus metrics over HTTP on this address, e.g. 127.0.0.1:9100 (server mode)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .help("How much the server prints: error, warn, info or debug (defaults to info)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .help("PEM certificate chain to serve TLS with (server mode, needs --tls-key)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .help("PEM private key of the TLS certificate (server mode)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .help("Connects to the server over TLS, trusting the key it presents on first contact unless --tls-ca or --tls-fingerprint is given")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("tls-ca")
+                .long("tls-ca")
+                .help("PEM file of the CA to trust the server's certificate by (implies --tls)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-fingerprint")
+                .long("tls-fingerprint")
+                .help("Trusts the server whose key has this fingerprint, as printed by the server (implies --tls)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("login")
+                .short("l")
+                .long("login")
+                .help("Logs in to the account registered under the username, prompting for its password")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("config")
             .short("c")
@@ -50,37 +159,219 @@ fn main() -> std::io::Result<()> {
             .help("Path to config file (defaults to .config/svchat/svchat.ini)")
             .takes_value(true)
         )
+        .subcommand(
+            SubCommand::with_name("admin")
+                .about("Manages a running server through its control socket")
+                .after_help(admin::COMMANDS)
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .help("Control socket of the server (defaults to control_socket in the config file)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Prints the server's reply as JSON")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .help("Command and its arguments, e.g. kick bob spamming")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
         .get_matches();
 
+    let mut config: config::Config = config::Config::default();
+    if let Some(path) = matches.value_of("config") {
+        config = config::Config::load(Path::new(path)).unwrap_or_else(|err| {
+            println!("{}", err);
+            exit(1);
+        });
+    }
+
+    if let Some(admin) = matches.subcommand_matches("admin") {
+        exit(admin_command(admin, &config));
+    }
+
     if matches.is_present("server") {
-        let port = matches.value_of("port").unwrap_or("6000");
-        println!("Starting server on port {}...", port);
-        server::start(port)?;
+        let mut settings = config.server.clone();
+        if let Err(err) = apply_server_flags(&matches, &mut settings) {
+            println!("{}", err);
+            exit(1);
+        }
+        log::set_level(settings.log_level);
+        let mut history = history::HistoryConfig {
+            max_file_size: settings.history_max_file_size,
+            backlog: settings.history_backlog,
+            ..history::HistoryConfig::default()
+        };
+        if let Some(dir) = &settings.history_dir {
+            history.dir = dir.clone();
+        }
+        let mut auth = accounts::AuthConfig::default();
+        if let Some(file) = &settings.user_db {
+            auth.user_db = file.clone();
+        }
+        auth.required = settings.require_auth;
+        let moderation = settings.moderation_db.clone()
+            .unwrap_or_else(|| config::data_dir().unwrap_or_default().join("moderation.json"));
+        let cert = matches.value_of("tls-cert").map(String::from).or_else(|| config.tls.cert.clone());
+        let key = matches.value_of("tls-key").map(String::from).or_else(|| config.tls.key.clone());
+        let tls = match (cert, key) {
+            (Some(cert), Some(key)) => {
+                let (tls, fingerprint) = tls::server_config(cert.as_ref(), key.as_ref())?;
+                println!("TLS enabled, key fingerprint {}", fingerprint);
+                Some(tls)
+            }
+            (None, None) => None,
+            _ => {
+                println!("TLS needs both a certificate and a key");
+                exit(1);
+            }
+        };
+        // The admin console's reload reads both again, just like startup
+        let config_path = matches.value_of("config").map(PathBuf::from);
+        let reload: server::Reload = Box::new(move || {
+            let mut settings = match &config_path {
+                Some(path) => config::Config::load(path)?.server,
+                None => config::Server::default(),
+            };
+            apply_server_flags(&matches, &mut settings)?;
+            Ok(settings)
+        });
+        println!("Starting server...");
+        if let Err(err) = server::start(settings, history, auth, moderation, tls, reload) {
+            println!("{}", err);
+            exit(1);
+        }
     } else {
         let ip = matches.value_of("address").unwrap_or("127.0.0.1");
         let port = matches.value_of("port").unwrap_or("6000");
-        let address = ip.to_string() + ":" + port;
+        // IPv6 literals need brackets before a port can follow
+        let address = match ip.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+            _ => format!("{}:{}", ip, port),
+        };
         let username = matches.value_of("username").unwrap_or(&gethostname().into_string().unwrap()).to_string();
+        let room = matches.value_of("room").map(String::from)
+            .or_else(config::load_last_room)
+            .unwrap_or_else(|| String::from(structs::DEFAULT_ROOM));
 
         if username.is_empty() {
             println!("Must enter username <-u username>");
             exit(0);
         }
 
-        let mut config: config::Config = config::Config::default();
-        if matches.is_present("config") {
-            let config_raw = fs::read_to_string(matches.value_of("config").unwrap_or("~/.config/svchat/svchat.toml")).unwrap();
-            config = toml::from_str(&config_raw).unwrap();
-        }
+        let ca = matches.value_of("tls-ca").map(String::from).or_else(|| config.tls.ca.clone());
+        let fingerprint = matches.value_of("tls-fingerprint").map(String::from).or_else(|| config.tls.fingerprint.clone());
+        let trust = if matches.is_present("tls") || config.tls.enabled || ca.is_some() || fingerprint.is_some() {
+            Some(match (fingerprint, ca) {
+                (Some(fingerprint), _) => tls::Trust::Fingerprint(fingerprint),
+                (None, Some(ca)) => tls::Trust::Ca(ca),
+                // Without a CA or pin, the key seen on first contact is remembered in known_hosts
+                (None, None) => tls::Trust::FirstUse,
+            })
+        } else {
+            None
+        };
+
+        let password = if matches.is_present("login") {
+            Some(rpassword::prompt_password(format!("Password for {}: ", username))?)
+        } else {
+            None
+        };
 
         println!(
             "Connecting {} to {}",
             username,
-            address.to_string() + &":".to_string() + port
+            address
         );
 
-        client::start(address, username, config).unwrap();
+        client::start(address, trust, username, password, room, config).unwrap();
     }
 
     Ok(())
 }
+
+/// Lets command line flags override the `[server]` section of the config file.
+fn apply_server_flags(matches: &ArgMatches, settings: &mut config::Server) -> Result<(), String> {
+    if let Some(addresses) = matches.values_of("address") {
+        settings.bind = addresses.map(String::from).collect();
+    }
+    if let Some(port) = matches.value_of("port") {
+        settings.port = port.parse().map_err(|_| format!("Invalid port {}, expected a number up to 65535", port))?;
+    }
+    if let Some(max) = matches.value_of("max-clients") {
+        settings.max_clients = Some(max.parse().map_err(|_| format!("Invalid --max-clients {}, expected a number", max))?);
+    }
+    if let Some(level) = matches.value_of("log-level") {
+        settings.log_level = level.parse()?;
+    }
+    if let Some(dir) = matches.value_of("history-dir") {
+        settings.history_dir = Some(dir.into());
+    }
+    if let Some(file) = matches.value_of("user-db") {
+        settings.user_db = Some(file.into());
+    }
+    if let Some(file) = matches.value_of("moderation-db") {
+        settings.moderation_db = Some(file.into());
+    }
+    if matches.is_present("require-auth") {
+        settings.require_auth = true;
+    }
+    if let Some(path) = matches.value_of("control-socket") {
+        settings.control_socket = Some(path.into());
+    }
+    if let Some(addr) = matches.value_of("metrics") {
+        settings.metrics = Some(addr.parse().map_err(|_| format!("Invalid --metrics {}, expected an address like 127.0.0.1:9100", addr))?);
+    }
+    settings.validate()
+}
+
+/// Runs `svchat admin` against a running server, returning the exit code.
+#[cfg(unix)]
+fn admin_command(matches: &ArgMatches, config: &config::Config) -> i32 {
+    let path = match matches.value_of("socket").map(PathBuf::from).or_else(|| config.server.control_socket.clone()) {
+        Some(path) => path,
+        None => {
+            println!("No control socket given, pass --socket or set control_socket in the [server] section");
+            return 1;
+        }
+    };
+    let words: Vec<&str> = matches.values_of("command").unwrap_or_default().collect();
+    let command = match admin::Command::parse(&words) {
+        Some(command) => command,
+        None => {
+            println!("Unknown command {}, see svchat admin --help", words.join(" "));
+            return 1;
+        }
+    };
+    let reply = match control::request(&path, &command) {
+        Ok(reply) => reply,
+        Err(err) => {
+            println!("Can't reach the server at {}: {}", path.display(), err);
+            return 1;
+        }
+    };
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string(&reply).unwrap());
+    } else {
+        for line in reply.lines() {
+            println!("{}", line);
+        }
+    }
+    match reply {
+        admin::Reply::Error { .. } => 1,
+        _ => 0,
+    }
+}
+
+#[cfg(not(unix))]
+fn admin_command(_: &ArgMatches, _: &config::Config) -> i32 {
+    println!("svchat admin needs a Unix system");
+    1
+}
@@ -0,0 +1,194 @@
+use std::{
+	collections::BTreeMap,
+	fmt::Write as _,
+	io::{self, BufRead, BufReader, Read, Write},
+	net::{SocketAddr, TcpListener, TcpStream},
+	sync::{atomic::{AtomicUsize, Ordering}, Arc},
+	thread,
+	time::Duration,
+};
+
+use crate::server::Handle;
+
+/// Upper bounds of the broadcast latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1];
+/// Longest a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+/// Scrapes served at once; connections beyond it are closed straight away.
+const MAX_SCRAPES: usize = 4;
+
+/// Counters the event loop keeps for the metrics endpoint.
+#[derive(Default)]
+pub struct Metrics {
+	pub accepted: u64,
+	pub rejected: u64,
+	/// Framed bytes, length prefixes included; TLS overhead isn't counted.
+	pub bytes_in: u64,
+	pub bytes_out: u64,
+	/// Frames over the size limit.
+	pub oversize_frames: u64,
+	/// Frames that aren't a command this server knows.
+	pub malformed_frames: u64,
+	/// Messages posted to each room.
+	pub messages_in: BTreeMap<String, u64>,
+	/// Copies of each room's messages delivered to its members.
+	pub messages_out: BTreeMap<String, u64>,
+	pub broadcast_latency: Histogram,
+}
+
+/// Counts of observations at or below each of `LATENCY_BUCKETS`.
+pub struct Histogram {
+	buckets: [u64; LATENCY_BUCKETS.len()],
+	sum: f64,
+	count: u64,
+}
+
+impl Default for Histogram {
+	fn default() -> Self {
+		Histogram { buckets: [0; LATENCY_BUCKETS.len()], sum: 0.0, count: 0 }
+	}
+}
+
+impl Histogram {
+	pub fn observe(&mut self, value: Duration) {
+		let seconds = value.as_secs_f64();
+		for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+			if seconds <= bound {
+				*bucket += 1;
+			}
+		}
+		self.sum += seconds;
+		self.count += 1;
+	}
+}
+
+/// What the event loop reads off its current state when scraped.
+pub struct Gauges<'a> {
+	pub uptime: Duration,
+	pub connections: usize,
+	pub users: usize,
+	pub members: Vec<(&'a str, usize)>,
+}
+
+impl Metrics {
+	/// The counters and `gauges` in the Prometheus text format.
+	pub fn render(&self, gauges: &Gauges) -> String {
+		let mut out = String::new();
+		metric(&mut out, "svchat_uptime_seconds", "gauge", "Seconds since the server started.");
+		writeln!(out, "svchat_uptime_seconds {}", gauges.uptime.as_secs_f64()).ok();
+		metric(&mut out, "svchat_connected_clients", "gauge", "Open client connections, including those still in the handshake.");
+		writeln!(out, "svchat_connected_clients {}", gauges.connections).ok();
+		metric(&mut out, "svchat_users", "gauge", "Clients past the handshake.");
+		writeln!(out, "svchat_users {}", gauges.users).ok();
+		metric(&mut out, "svchat_rooms", "gauge", "Rooms on the server.");
+		writeln!(out, "svchat_rooms {}", gauges.members.len()).ok();
+		metric(&mut out, "svchat_room_members", "gauge", "Clients in each room.");
+		for (room, members) in &gauges.members {
+			writeln!(out, "svchat_room_members{{room=\"{}\"}} {}", escape(room), members).ok();
+		}
+
+		metric(&mut out, "svchat_connections_accepted_total", "counter", "Connections accepted.");
+		writeln!(out, "svchat_connections_accepted_total {}", self.accepted).ok();
+		metric(&mut out, "svchat_connections_rejected_total", "counter", "Connections turned away during the handshake or for a full server.");
+		writeln!(out, "svchat_connections_rejected_total {}", self.rejected).ok();
+		metric(&mut out, "svchat_received_bytes_total", "counter", "Framed bytes received from clients.");
+		writeln!(out, "svchat_received_bytes_total {}", self.bytes_in).ok();
+		metric(&mut out, "svchat_sent_bytes_total", "counter", "Framed bytes sent to clients.");
+		writeln!(out, "svchat_sent_bytes_total {}", self.bytes_out).ok();
+		metric(&mut out, "svchat_frame_errors_total", "counter", "Frames from clients that couldn't be decoded.");
+		writeln!(out, "svchat_frame_errors_total{{kind=\"oversize\"}} {}", self.oversize_frames).ok();
+		writeln!(out, "svchat_frame_errors_total{{kind=\"malformed\"}} {}", self.malformed_frames).ok();
+
+		metric(&mut out, "svchat_room_messages_received_total", "counter", "Messages posted to each room.");
+		for (room, count) in &self.messages_in {
+			writeln!(out, "svchat_room_messages_received_total{{room=\"{}\"}} {}", escape(room), count).ok();
+		}
+		metric(&mut out, "svchat_room_messages_sent_total", "counter", "Messages delivered to the members of each room.");
+		for (room, count) in &self.messages_out {
+			writeln!(out, "svchat_room_messages_sent_total{{room=\"{}\"}} {}", escape(room), count).ok();
+		}
+
+		let latency = &self.broadcast_latency;
+		metric(&mut out, "svchat_broadcast_seconds", "histogram", "Time taken to queue an event for every member of a room.");
+		for (count, bound) in latency.buckets.iter().zip(LATENCY_BUCKETS) {
+			writeln!(out, "svchat_broadcast_seconds_bucket{{le=\"{}\"}} {}", bound, count).ok();
+		}
+		writeln!(out, "svchat_broadcast_seconds_bucket{{le=\"+Inf\"}} {}", latency.count).ok();
+		writeln!(out, "svchat_broadcast_seconds_sum {}", latency.sum).ok();
+		writeln!(out, "svchat_broadcast_seconds_count {}", latency.count).ok();
+		out
+	}
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+	writeln!(out, "# HELP {} {}", name, help).ok();
+	writeln!(out, "# TYPE {} {}", name, kind).ok();
+}
+
+/// Escapes a label value as the text format wants.
+fn escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the metrics over plain HTTP at `/metrics` on `addr`.
+pub fn listen(addr: SocketAddr, handle: Handle) -> io::Result<()> {
+	let listener = TcpListener::bind(addr)
+		.map_err(|err| io::Error::new(err.kind(), format!("Can't serve metrics on {}: {}", addr, err)))?;
+	log!(Info, "Metrics at http://{}/metrics", listener.local_addr()?);
+	let scrapes = Arc::new(AtomicUsize::new(0));
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			match stream {
+				Ok(_) if scrapes.load(Ordering::SeqCst) >= MAX_SCRAPES => log!(Debug, "Too many metrics scrapes at once"),
+				Ok(stream) => {
+					let handle = handle.clone();
+					let scrapes = scrapes.clone();
+					scrapes.fetch_add(1, Ordering::SeqCst);
+					thread::spawn(move || {
+						if let Err(err) = scrape(stream, &handle) {
+							log!(Debug, "Failed to serve metrics: {}", err);
+						}
+						scrapes.fetch_sub(1, Ordering::SeqCst);
+					});
+				}
+				Err(err) => log!(Warn, "Failed to accept a metrics connection: {}", err),
+			}
+		}
+	});
+	Ok(())
+}
+
+/// Answers one HTTP request; anything but `GET /metrics` gets an error status.
+fn scrape(mut stream: TcpStream, handle: &Handle) -> io::Result<()> {
+	stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+	stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+	// Nothing past the first MAX_REQUEST_HEAD bytes is ever read
+	let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_HEAD as u64));
+	let mut request = String::new();
+	reader.read_line(&mut request)?;
+	// The headers don't matter, but are read so closing doesn't reset the connection
+	loop {
+		let mut header = String::new();
+		if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+			break;
+		}
+	}
+
+	let mut parts = request.split_whitespace();
+	let (status, body) = match (parts.next(), parts.next()) {
+		(Some("GET"), Some("/metrics")) => match handle.metrics() {
+			Some(body) => ("200 OK", body),
+			None => ("503 Service Unavailable", String::from("The server is shutting down\n")),
+		},
+		(Some("GET"), _) => ("404 Not Found", String::from("Metrics are at /metrics\n")),
+		_ => ("405 Method Not Allowed", String::from("Only GET is supported\n")),
+	};
+	let content_type = if status.starts_with("200") { "text/plain; version=0.0.4" } else { "text/plain" };
+	write!(
+		stream,
+		"HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status, content_type, body.len(), body
+	)?;
+	stream.flush()
+}
@@ -0,0 +1,363 @@
+use std::{collections::HashMap, fmt, fs, io, net::IpAddr, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+/// What a user may do in a room, from least to most.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+	Member,
+	/// May kick, ban and mute members.
+	Operator,
+	/// Created the room; may also appoint operators.
+	Owner,
+}
+
+impl fmt::Display for Role {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Role::Member => write!(f, "member"),
+			Role::Operator => write!(f, "operator"),
+			Role::Owner => write!(f, "owner"),
+		}
+	}
+}
+
+/// Who a ban or mute applies to.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+	/// Lowercased account or username.
+	Name(String),
+	Ip(IpAddr),
+}
+
+impl Target {
+	/// An IP address if `target` parses as one, a username otherwise.
+	pub fn parse(target: &str) -> Target {
+		match target.parse() {
+			Ok(ip) => Target::Ip(ip),
+			Err(_) => Target::Name(target.to_lowercase()),
+		}
+	}
+}
+
+impl fmt::Display for Target {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Target::Name(name) => write!(f, "{}", name),
+			Target::Ip(ip) => write!(f, "{}", ip),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Sanction {
+	pub target: Target,
+	/// Lowercased username it was issued against, so a sanction kept under an
+	/// address can still be lifted by name.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub username: Option<String>,
+	pub by: String,
+	pub reason: Option<String>,
+	/// Lifted once this has passed; permanent if missing.
+	pub until: Option<DateTime<Utc>>,
+}
+
+impl Sanction {
+	fn active(&self) -> bool {
+		self.until.is_none_or(|until| until > Utc::now())
+	}
+
+	/// Whether lifting the sanctions of `target` lifts this one.
+	fn lifted_by(&self, target: &Target) -> bool {
+		self.target == *target || matches!(target, Target::Name(name) if self.username.as_ref() == Some(name))
+	}
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct RoomModeration {
+	owner: Option<String>,
+	operators: Vec<String>,
+	bans: Vec<Sanction>,
+	mutes: Vec<Sanction>,
+}
+
+/// Roles, bans and mutes of every room, saved to the moderation file on every change.
+///
+/// Roles belong to lowercased account names. Sanctions are kept under the
+/// account of a user who logged in, and under the address of one who didn't,
+/// since anyone can take over a username.
+pub struct Moderation {
+	path: PathBuf,
+	rooms: HashMap<String, RoomModeration>,
+}
+
+impl Moderation {
+	/// Reads the moderation file, starting empty if it doesn't exist yet.
+	pub fn load(path: PathBuf) -> io::Result<Moderation> {
+		let rooms = match fs::read(&path) {
+			Ok(content) => serde_json::from_slice(&content)?,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+			Err(err) => return Err(err),
+		};
+		Ok(Moderation { path, rooms })
+	}
+
+	/// Rooms with roles or sanctions saved, which outlive their members.
+	pub fn rooms(&self) -> impl Iterator<Item = &str> {
+		self.rooms.keys().map(String::as_str)
+	}
+
+	/// Records a newly created room, making `owner` its owner unless it already has one.
+	/// Roles and sanctions saved under the same name are kept.
+	pub fn create(&mut self, room: &str, owner: Option<&str>) {
+		let r = self.rooms.entry(room.to_string()).or_default();
+		if r.owner.is_none() {
+			r.owner = owner.map(String::from);
+		}
+		self.save();
+	}
+
+	pub fn owner(&self, room: &str) -> Option<&str> {
+		self.rooms.get(room)?.owner.as_deref()
+	}
+
+	pub fn role(&self, room: &str, user: &str) -> Role {
+		match self.rooms.get(room) {
+			Some(r) if r.owner.as_deref() == Some(user) => Role::Owner,
+			Some(r) if r.operators.iter().any(|op| op == user) => Role::Operator,
+			_ => Role::Member,
+		}
+	}
+
+	/// Gives `user` a role in a room. Making someone owner demotes the old owner to operator.
+	pub fn set_role(&mut self, room: &str, user: &str, role: Role) {
+		let r = self.rooms.entry(room.to_string()).or_default();
+		r.operators.retain(|op| op != user);
+		if r.owner.as_deref() == Some(user) {
+			r.owner = None;
+		}
+		match role {
+			Role::Member => (),
+			Role::Operator => r.operators.push(user.to_string()),
+			Role::Owner => {
+				if let Some(old) = r.owner.replace(user.to_string()) {
+					r.operators.push(old);
+				}
+			}
+		}
+		self.save();
+	}
+
+	/// The ban keeping a user, by name or address, out of a room.
+	pub fn banned(&self, room: &str, user: &str, ip: IpAddr) -> Option<&Sanction> {
+		self.rooms.get(room)?.bans.iter().find(|ban| {
+			ban.active() && (ban.target == Target::Name(user.to_string()) || ban.target == Target::Ip(ip))
+		})
+	}
+
+	pub fn muted(&self, room: &str, user: &str, ip: IpAddr) -> Option<&Sanction> {
+		self.rooms.get(room)?.mutes.iter().find(|mute| {
+			mute.active() && (mute.target == Target::Name(user.to_string()) || mute.target == Target::Ip(ip))
+		})
+	}
+
+	/// Whether a user is banned from or muted in any room.
+	pub fn sanctioned(&self, user: &str, ip: IpAddr) -> bool {
+		self.rooms.keys().any(|room| self.banned(room, user, ip).is_some() || self.muted(room, user, ip).is_some())
+	}
+
+	/// Bans a target from a room, replacing any earlier ban of it.
+	pub fn ban(&mut self, room: &str, ban: Sanction) {
+		let bans = &mut self.rooms.entry(room.to_string()).or_default().bans;
+		bans.retain(|b| b.target != ban.target);
+		bans.push(ban);
+		self.save();
+	}
+
+	/// Lifts a ban of `target`, or one issued under its name, returning whether there was one.
+	pub fn unban(&mut self, room: &str, target: &Target) -> bool {
+		let lifted = self.rooms.get_mut(room).is_some_and(|r| {
+			let before = r.bans.len();
+			r.bans.retain(|b| !b.lifted_by(target) || !b.active());
+			r.bans.len() != before
+		});
+		self.save();
+		lifted
+	}
+
+	pub fn mute(&mut self, room: &str, mute: Sanction) {
+		let mutes = &mut self.rooms.entry(room.to_string()).or_default().mutes;
+		mutes.retain(|m| m.target != mute.target);
+		mutes.push(mute);
+		self.save();
+	}
+
+	pub fn unmute(&mut self, room: &str, target: &Target) -> bool {
+		let lifted = self.rooms.get_mut(room).is_some_and(|r| {
+			let before = r.mutes.len();
+			r.mutes.retain(|m| !m.lifted_by(target) || !m.active());
+			r.mutes.len() != before
+		});
+		self.save();
+		lifted
+	}
+
+	/// Drops lifted bans and mutes and writes the file like the user database,
+	/// through a temporary file. A failure is logged; the change still holds until restart.
+	fn save(&mut self) {
+		for r in self.rooms.values_mut() {
+			r.bans.retain(Sanction::active);
+			r.mutes.retain(Sanction::active);
+		}
+		if let Err(err) = self.write() {
+			log!(Error, "Failed to save moderation file: {}", err);
+		}
+	}
+
+	fn write(&self) -> io::Result<()> {
+		if let Some(dir) = self.path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		let tmp = self.path.with_extension("tmp");
+		fs::write(&tmp, serde_json::to_vec_pretty(&self.rooms)?)?;
+		fs::rename(&tmp, &self.path)
+	}
+}
+
+/// Parses durations like `90s`, `10m`, `2h` or `7d`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+	let (split, _) = text.char_indices().last()?;
+	let (count, unit) = text.split_at(split);
+	let count: u64 = count.parse().ok()?;
+	let unit = match unit {
+		"s" => 1,
+		"m" => 60,
+		"h" => 60 * 60,
+		"d" => 24 * 60 * 60,
+		_ => return None,
+	};
+	Some(Duration::from_secs(count.checked_mul(unit)?))
+}
+
+/// Splits an optional leading duration like `10m` off the rest of a command,
+/// returning it in seconds along with the other words joined, if there are any.
+pub fn duration_and_text(words: &[&str]) -> (Option<u64>, Option<String>) {
+	let (seconds, rest) = match words.first().and_then(|word| parse_duration(word)) {
+		Some(duration) => (Some(duration.as_secs()), &words[1..]),
+		None => (None, words),
+	};
+	let text = if rest.is_empty() { None } else { Some(rest.join(" ")) };
+	(seconds, text)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{env, process};
+
+	use super::*;
+
+	/// A moderation file of its own for each test, gone before it starts.
+	fn temp_path(name: &str) -> PathBuf {
+		let path = env::temp_dir().join(format!("svchat-moderation-{}-{}.json", process::id(), name));
+		fs::remove_file(&path).ok();
+		path
+	}
+
+	fn sanction(target: Target, username: Option<&str>, until: Option<DateTime<Utc>>) -> Sanction {
+		Sanction { target, username: username.map(String::from), by: String::from("op"), reason: None, until }
+	}
+
+	#[test]
+	fn parses_durations() {
+		assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+		assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+		assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+		assert_eq!(parse_duration("7d"), Some(Duration::from_secs(7 * 86400)));
+		assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+	}
+
+	#[test]
+	fn rejects_what_is_not_a_duration() {
+		for text in ["", "s", "10", "10w", "-5m", "1.5h", "ten", "5é", "spam"] {
+			assert_eq!(parse_duration(text), None, "{:?}", text);
+		}
+		// Too many days to count in seconds
+		assert_eq!(parse_duration(&format!("{}d", u64::MAX)), None);
+	}
+
+	#[test]
+	fn splits_a_leading_duration_off_the_text() {
+		assert_eq!(duration_and_text(&["10m", "spamming", "links"]), (Some(600), Some(String::from("spamming links"))));
+		assert_eq!(duration_and_text(&["2h"]), (Some(7200), None));
+		assert_eq!(duration_and_text(&["spamming"]), (None, Some(String::from("spamming"))));
+		assert_eq!(duration_and_text(&[]), (None, None));
+	}
+
+	#[test]
+	fn roles_rank_from_member_to_owner() {
+		assert!(Role::Member < Role::Operator);
+		assert!(Role::Operator < Role::Owner);
+	}
+
+	#[test]
+	fn new_owner_demotes_the_old_one() {
+		let path = temp_path("owner");
+		let mut moderation = Moderation::load(path.clone()).unwrap();
+		moderation.create("room", Some("alice"));
+		moderation.set_role("room", "bob", Role::Operator);
+
+		moderation.set_role("room", "bob", Role::Owner);
+		assert_eq!(moderation.role("room", "bob"), Role::Owner);
+		assert_eq!(moderation.role("room", "alice"), Role::Operator);
+
+		// Creating the room again keeps its owner
+		moderation.create("room", Some("carol"));
+		assert_eq!(moderation.owner("room"), Some("bob"));
+		fs::remove_file(path).ok();
+	}
+
+	#[test]
+	fn address_bans_are_lifted_by_the_name_they_were_issued_under() {
+		let ip: IpAddr = "192.0.2.7".parse().unwrap();
+		let ban = sanction(Target::Ip(ip), Some("bob"), None);
+		assert!(ban.lifted_by(&Target::Ip(ip)));
+		assert!(ban.lifted_by(&Target::Name(String::from("bob"))));
+		assert!(!ban.lifted_by(&Target::Name(String::from("carol"))));
+
+		let path = temp_path("unban");
+		let mut moderation = Moderation::load(path.clone()).unwrap();
+		moderation.ban("room", ban);
+		assert!(moderation.banned("room", "anyone", ip).is_some());
+		assert!(!moderation.unban("room", &Target::Name(String::from("carol"))));
+		assert!(moderation.unban("room", &Target::Name(String::from("bob"))));
+		assert!(moderation.banned("room", "anyone", ip).is_none());
+		fs::remove_file(path).ok();
+	}
+
+	#[test]
+	fn survives_a_restart() {
+		let path = temp_path("restart");
+		let ip: IpAddr = "2001:db8::1".parse().unwrap();
+		let mut moderation = Moderation::load(path.clone()).unwrap();
+		moderation.create("room", Some("alice"));
+		moderation.set_role("room", "bob", Role::Operator);
+		moderation.ban("room", sanction(Target::Ip(ip), Some("mallory"), None));
+		moderation.mute("room", sanction(Target::Name(String::from("eve")), None, Some(Utc::now() + chrono::Duration::hours(1))));
+		// Already over, so it isn't kept
+		moderation.ban("room", sanction(Target::Name(String::from("trent")), None, Some(Utc::now() - chrono::Duration::hours(1))));
+
+		let moderation = Moderation::load(path.clone()).unwrap();
+		assert_eq!(moderation.rooms().collect::<Vec<&str>>(), vec!["room"]);
+		assert_eq!(moderation.owner("room"), Some("alice"));
+		assert_eq!(moderation.role("room", "bob"), Role::Operator);
+		assert!(moderation.banned("room", "mallory", ip).is_some());
+		assert!(moderation.muted("room", "eve", "192.0.2.1".parse().unwrap()).is_some());
+		assert!(moderation.banned("room", "trent", "192.0.2.1".parse().unwrap()).is_none());
+		assert!(!fs::read_to_string(&path).unwrap().contains("trent"));
+		fs::remove_file(path).ok();
+	}
+}
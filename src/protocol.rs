@@ -0,0 +1,142 @@
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use tui::style::Color;
+
+use crate::moderation::Role;
+use crate::structs::{ConnectionRequest, DirectMsg, Msg, Sealed};
+
+/// Version spoken by this build, sent in `Hello` and `Welcome`.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Longest message content, in bytes, that either end sends or accepts.
+pub const MAX_CONTENT_SIZE: usize = 4096;
+/// Longest sealed direct message: `MAX_CONTENT_SIZE` plus the 16 byte tag, in base64.
+pub const MAX_SEALED_SIZE: usize = (MAX_CONTENT_SIZE + 16).div_ceil(3) * 4;
+
+/// Optional protocol features a peer can advertise during the handshake.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+	Compression,
+	History,
+	TypingIndicators,
+	/// Anything advertised by a newer peer that this build doesn't know about.
+	#[serde(other)]
+	Unknown,
+}
+
+/// Capabilities implemented by this build.
+pub const CAPABILITIES: &[Capability] = &[Capability::History];
+
+/// Keeps the offered capabilities that this build also implements.
+pub fn negotiate(offered: &[Capability]) -> Vec<Capability> {
+	offered.iter().filter(|c| CAPABILITIES.contains(c)).copied().collect()
+}
+
+/// How the server reacts to a client going over its rate limit.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Throttle {
+	/// The frame was dropped.
+	Warning,
+	/// Chat from the client is refused for a while.
+	Mute { seconds: u64 },
+	/// The connection is being closed.
+	Disconnect,
+}
+
+/// What a moderator did to a user, or to an address for bans.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModAction {
+	Kicked,
+	Banned,
+	Unbanned,
+	Muted,
+	Unmuted,
+}
+
+/// Everything a client can send to the server, one per frame.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+	Hello(ConnectionRequest),
+	Message { room: String, content: String, color: Color },
+	/// A direct message, sealed client-side for `to`.
+	Direct { to: String, sealed: Sealed, color: Color },
+	/// Asks for the key a user published, to seal direct messages for them.
+	GetKey { username: String },
+	Nick { username: String },
+	/// Registers the current username as an account and logs in to it.
+	Register { password: String },
+	CreateRoom { room: String },
+	Join { room: String },
+	Leave { room: String },
+	ListRooms,
+	Who { room: String },
+	/// Asks for up to `limit` logged messages of `room` older than sequence number `before`.
+	History { room: String, before: u64, limit: usize },
+	Search { room: String, query: String },
+	/// Asks for the messages around `seq`, e.g. to show a search result in context.
+	Context { room: String, seq: u64 },
+	/// Removes a user from a room; they may come back.
+	Kick { room: String, username: String, reason: Option<String> },
+	/// Keeps a username or IP address out of a room, for `seconds` or for good.
+	Ban { room: String, target: String, seconds: Option<u64>, reason: Option<String> },
+	Unban { room: String, target: String },
+	/// Stops a user from posting in a room, for `seconds` or until unmuted.
+	Mute { room: String, username: String, seconds: Option<u64>, reason: Option<String> },
+	Unmute { room: String, username: String },
+	/// Appoints or removes an operator, or hands the room over. Owners only.
+	SetRole { room: String, username: String, role: Role },
+}
+
+impl ClientCommand {
+	/// Whether the command carries a password and must never be logged.
+	pub fn has_password(&self) -> bool {
+		match self {
+			ClientCommand::Hello(request) => request.password.is_some(),
+			ClientCommand::Register { .. } => true,
+			_ => false,
+		}
+	}
+}
+
+/// Everything the server can send to a client, one per frame.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+	Welcome { version: u32, capabilities: Vec<Capability> },
+	Rejected { reason: String },
+	Message(Msg),
+	Direct(DirectMsg),
+	Joined { room: String },
+	Left { room: String },
+	/// Long lists come in pages; `more` is set on all but the last.
+	Rooms { rooms: Vec<String>, #[serde(default)] more: bool },
+	NickChanged { old: String, new: String },
+	Registered { username: String },
+	/// `key` is missing if the user is offline or published none.
+	PublicKey { username: String, key: Option<String> },
+	/// Paged like `Rooms`.
+	Members { room: String, users: Vec<String>, #[serde(default)] more: bool },
+	/// Messages logged before the client joined, oldest first.
+	Backlog { room: String, messages: Vec<Msg> },
+	HistoryPage { room: String, messages: Vec<Msg> },
+	SearchResults { room: String, query: String, results: Vec<Msg> },
+	Context { room: String, seq: u64, messages: Vec<Msg> },
+	UserJoined { room: String, username: String },
+	UserLeft { room: String, username: String },
+	UserQuit { username: String },
+	Notice { content: String },
+	Throttled { action: Throttle, reason: String },
+	/// `until` is missing for permanent bans and mutes.
+	Moderation { room: String, action: ModAction, target: String, by: String, reason: Option<String>, until: Option<DateTime<Utc>> },
+	RoleChanged { room: String, username: String, role: Role, by: String },
+	Error { reason: String },
+	/// The client broke the protocol and the connection is being closed.
+	ProtocolError { reason: String },
+	/// The server is going away; `restart_in` is roughly how many seconds until it is back.
+	Shutdown { reason: Option<String>, restart_in: Option<u64> },
+}
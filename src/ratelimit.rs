@@ -0,0 +1,248 @@
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// `[server.rate_limit]`: how much one connection may send.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+	/// Frames per second a client may keep sending
+	pub messages_per_sec: f64,
+	/// Frames that may arrive at once above the sustained rate
+	pub message_burst: f64,
+	pub bytes_per_sec: f64,
+	pub byte_burst: f64,
+	/// Frames over the limit that only get a warning before a mute
+	pub warnings: u32,
+	pub mute_secs: u64,
+	/// Mutes before the next offence disconnects the client
+	pub mutes: u32,
+}
+
+impl Default for RateLimitConfig {
+	fn default() -> Self {
+		Self {
+			messages_per_sec: 5.0,
+			message_burst: 10.0,
+			bytes_per_sec: 16.0 * 1024.0,
+			byte_burst: 64.0 * 1024.0,
+			warnings: 3,
+			mute_secs: 30,
+			mutes: 2,
+		}
+	}
+}
+
+impl RateLimitConfig {
+	pub fn validate(&self) -> Result<(), String> {
+		let rates = [
+			("messages_per_sec", self.messages_per_sec),
+			("message_burst", self.message_burst),
+			("bytes_per_sec", self.bytes_per_sec),
+			("byte_burst", self.byte_burst),
+		];
+		for (name, value) in rates {
+			if !(value > 0.0 && value.is_finite()) {
+				return Err(format!("server.rate_limit.{} must be a positive number, got {}", name, value));
+			}
+		}
+		// A burst smaller than one frame would throttle every frame
+		if self.message_burst < 1.0 {
+			return Err(String::from("server.rate_limit.message_burst must be at least 1"));
+		}
+		if self.byte_burst < crate::frame::DEFAULT_MAX_FRAME_SIZE as f64 {
+			return Err(format!("server.rate_limit.byte_burst must be at least the {} byte frame limit", crate::frame::DEFAULT_MAX_FRAME_SIZE));
+		}
+		Ok(())
+	}
+}
+
+struct TokenBucket {
+	rate: f64,
+	capacity: f64,
+	tokens: f64,
+	last: Instant,
+}
+
+impl TokenBucket {
+	fn new(rate: f64, capacity: f64, now: Instant) -> TokenBucket {
+		TokenBucket { rate, capacity, tokens: capacity, last: now }
+	}
+
+	fn refill(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+		self.last = now;
+	}
+
+	fn has(&self, amount: f64) -> bool {
+		self.tokens >= amount
+	}
+}
+
+/// What to do with a frame after checking it against the limits.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+	Allow,
+	/// Over the limit while already muted: drop the frame quietly.
+	Drop,
+	/// Over the limit: drop the frame and warn the client.
+	Warn,
+	/// Over the limit too often: drop the frame and mute the client.
+	Mute(Duration),
+	/// Still flooding after every mute.
+	Disconnect,
+}
+
+/// Token buckets for one connection, escalating from warnings to mutes to a
+/// disconnect as the client keeps going over them.
+pub struct RateLimiter {
+	config: RateLimitConfig,
+	messages: TokenBucket,
+	bytes: TokenBucket,
+	strikes: u32,
+	last_strike: Option<Instant>,
+	mutes: u32,
+	muted_until: Option<Instant>,
+}
+
+impl RateLimiter {
+	/// A limiter with full buckets as of `now`.
+	pub fn new(config: RateLimitConfig, now: Instant) -> RateLimiter {
+		RateLimiter {
+			messages: TokenBucket::new(config.messages_per_sec, config.message_burst, now),
+			bytes: TokenBucket::new(config.bytes_per_sec, config.byte_burst, now),
+			config,
+			strikes: 0,
+			last_strike: None,
+			mutes: 0,
+			muted_until: None,
+		}
+	}
+
+	/// Charges a frame of `size` bytes, arriving at `now`, to the buckets.
+	pub fn check(&mut self, size: usize, now: Instant) -> Verdict {
+		self.messages.refill(now);
+		self.bytes.refill(now);
+		if self.messages.has(1.0) && self.bytes.has(size as f64) {
+			self.messages.tokens -= 1.0;
+			self.bytes.tokens -= size as f64;
+			return Verdict::Allow;
+		}
+		if self.muted_for(now).is_some() {
+			return Verdict::Drop;
+		}
+
+		// A client that calmed down for a whole mute period starts over with warnings
+		let mute = Duration::from_secs(self.config.mute_secs);
+		if self.last_strike.is_some_and(|last| now.duration_since(last) > mute) {
+			self.strikes = 0;
+		}
+		self.strikes += 1;
+		self.last_strike = Some(now);
+		if self.strikes <= self.config.warnings {
+			return Verdict::Warn;
+		}
+		if self.mutes >= self.config.mutes {
+			return Verdict::Disconnect;
+		}
+		self.strikes = 0;
+		self.mutes += 1;
+		self.muted_until = Some(now + mute);
+		Verdict::Mute(mute)
+	}
+
+	/// Time left on the current mute as of `now`, if any.
+	pub fn muted_for(&self, now: Instant) -> Option<Duration> {
+		let left = self.muted_until?.saturating_duration_since(now);
+		if left.is_zero() {
+			None
+		} else {
+			Some(left)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// One frame per second with no room for bursts, two warnings and one mute.
+	fn strict() -> RateLimitConfig {
+		RateLimitConfig {
+			messages_per_sec: 1.0,
+			message_burst: 1.0,
+			warnings: 2,
+			mute_secs: 30,
+			mutes: 1,
+			..RateLimitConfig::default()
+		}
+	}
+
+	fn secs(n: u64) -> Duration {
+		Duration::from_secs(n)
+	}
+
+	#[test]
+	fn escalates_from_warnings_to_a_mute_to_a_disconnect() {
+		let start = Instant::now();
+		let mut limiter = RateLimiter::new(strict(), start);
+
+		assert_eq!(limiter.check(10, start), Verdict::Allow);
+		assert_eq!(limiter.check(10, start), Verdict::Warn);
+		assert_eq!(limiter.check(10, start), Verdict::Warn);
+		assert_eq!(limiter.check(10, start), Verdict::Mute(secs(30)));
+		assert_eq!(limiter.muted_for(start + secs(10)), Some(secs(20)));
+		// Only frames over the limit are dropped during a mute, and without a word
+		assert_eq!(limiter.check(10, start + secs(10)), Verdict::Allow);
+		assert_eq!(limiter.check(10, start + secs(10)), Verdict::Drop);
+
+		// Out of the mute it takes the warnings again, then the next offence is the last
+		let after = start + secs(31);
+		assert_eq!(limiter.muted_for(after), None);
+		assert_eq!(limiter.check(10, after), Verdict::Allow);
+		assert_eq!(limiter.check(10, after), Verdict::Warn);
+		assert_eq!(limiter.check(10, after), Verdict::Warn);
+		assert_eq!(limiter.check(10, after), Verdict::Disconnect);
+	}
+
+	#[test]
+	fn forgets_warnings_after_a_quiet_mute_period() {
+		let start = Instant::now();
+		let mut limiter = RateLimiter::new(strict(), start);
+
+		assert_eq!(limiter.check(10, start), Verdict::Allow);
+		assert_eq!(limiter.check(10, start), Verdict::Warn);
+		assert_eq!(limiter.check(10, start), Verdict::Warn);
+
+		let later = start + secs(31);
+		assert_eq!(limiter.check(10, later), Verdict::Allow);
+		assert_eq!(limiter.check(10, later), Verdict::Warn);
+	}
+
+	#[test]
+	fn keeps_warnings_within_a_mute_period() {
+		let start = Instant::now();
+		let mut limiter = RateLimiter::new(strict(), start);
+
+		assert_eq!(limiter.check(10, start), Verdict::Allow);
+		assert_eq!(limiter.check(10, start), Verdict::Warn);
+		assert_eq!(limiter.check(10, start), Verdict::Warn);
+
+		let soon = start + secs(5);
+		assert_eq!(limiter.check(10, soon), Verdict::Allow);
+		assert_eq!(limiter.check(10, soon), Verdict::Mute(secs(30)));
+	}
+
+	#[test]
+	fn charges_bytes_as_well_as_frames() {
+		let config = RateLimitConfig { byte_burst: 100.0, bytes_per_sec: 10.0, ..strict() };
+		let start = Instant::now();
+		let mut limiter = RateLimiter::new(config, start);
+
+		assert_eq!(limiter.check(100, start), Verdict::Allow);
+		// A frame's worth of time later the frame bucket is full but the byte one isn't
+		assert_eq!(limiter.check(100, start + secs(1)), Verdict::Warn);
+		assert_eq!(limiter.check(100, start + secs(10)), Verdict::Allow);
+	}
+}
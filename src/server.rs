@@ -1,85 +1,1576 @@
-use std::{net::{SocketAddr, TcpListener, TcpStream}, io::{ErrorKind, Read, Write}, sync::{Arc, Mutex, mpsc}, thread::{self, sleep}, time::Duration, collections::HashMap, mem::size_of_val};
-
-use crate::structs::{Msg, MessageWrapper, MsgType, Connection, ConnectionRequest, RoomList};
-
-fn handle_client(mut stream: TcpStream, addr: SocketAddr, tx: mpsc::Sender<Msg>, roomlist: &mut RoomList<'a>) {
-	thread::spawn(move || loop {
-		let mut buf_sz = [0; std::mem::size_of::<usize>()];
-		
-		match stream.read_exact(&mut buf_sz) {
-			Ok(_) => {
-				let json_sz = usize::from_be_bytes(buf_sz);
-				println!("{}", json_sz);
-				let mut msg_buf: Vec<u8> = vec![0; json_sz];
-				match stream.read_exact(&mut msg_buf) {
-					Ok(_) => {
-						println!("{}", String::from_utf8(msg_buf.clone()).unwrap());
-						let wrapped_msg: MessageWrapper = serde_json::from_str(String::from_utf8(msg_buf).unwrap().as_str()).unwrap();
-						match wrapped_msg.msg_type {
-							MsgType::ConnectionRequest => {
-								let request: ConnectionRequest = serde_json::from_str(&wrapped_msg.msg).unwrap();
-								if roomlist.rooms.contains_key(&request.room) {
-									// TODO handle client assignment to room
-									roomlist.rooms.get_mut(&request.room).unwrap().add_user(addr);
-								}
-							}
-							MsgType::Message => {
-								let msg: Msg = serde_json::from_str(String::from_utf8(wrapped_msg.msg.into_bytes()).unwrap().as_str()).unwrap();
-								tx.send(msg).unwrap();
-							}
+use std::{collections::{HashMap, HashSet}, io::{self, ErrorKind}, mem, net::{IpAddr, Shutdown, SocketAddr, ToSocketAddrs}, path::PathBuf, sync::{mpsc, Arc}, time::{Duration, Instant}};
+
+use chrono::{DateTime, Utc};
+use mio::{net::TcpListener, Events, Interest, Poll, Registry, Token, Waker};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::accounts::{self, Accounts, AuthConfig, Job, Outcome};
+use crate::admin::{Command, Reply, RoomInfo, Stats, UserInfo};
+use crate::console::Console;
+#[cfg(unix)]
+use crate::control::ControlSocket;
+use crate::config;
+use crate::history::{History, HistoryConfig, Search};
+use crate::frame::{FrameDecoder, FrameEncoder, FrameError, DEFAULT_MAX_FRAME_SIZE, HEADER_SIZE};
+use crate::metrics::{self, Gauges, Metrics};
+use crate::moderation::{Moderation, Role, Sanction, Target};
+use crate::protocol::{self, Capability, ClientCommand, ModAction, ServerEvent, Throttle, MAX_CONTENT_SIZE, MAX_SEALED_SIZE, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use crate::ratelimit::{RateLimiter, Verdict};
+use crate::structs::{Connection, ConnectionRequest, DirectMsg, Msg, RoomList, DEFAULT_ROOM};
+use crate::tls::Stream;
+
+/// Listeners take the tokens below this.
+const MAX_LISTENERS: usize = 64;
+const FIRST_CLIENT: usize = 1024;
+/// Wakes the event loop when a `Control` message is waiting.
+const WAKER: Token = Token(MAX_LISTENERS);
+const MAX_USERNAME: usize = 32;
+/// Sender of what the admin says in rooms; the space keeps any client from taking the name.
+const ADMIN_SENDER: &str = "server admin";
+/// Longer than any base64 x25519 key, which is 44 characters.
+const MAX_PUBLIC_KEY: usize = 64;
+/// Most messages returned for one history page.
+const MAX_HISTORY_PAGE: usize = 200;
+const MAX_SEARCH_RESULTS: usize = 50;
+/// Messages shown on each side of a search result.
+const CONTEXT_SIZE: u64 = 5;
+/// Names per page of a room or member list; a page of the longest names still fits in a frame.
+const LIST_PAGE: usize = 256;
+/// Bytes a client may leave unread before it is cut off for falling behind.
+const MAX_QUEUED: usize = 4 * DEFAULT_MAX_FRAME_SIZE;
+/// Frames read from one client before the others get a turn.
+const MAX_FRAMES_PER_READ: usize = 32;
+/// Password checks one address may have running at once.
+const MAX_PENDING_LOGINS: usize = 2;
+/// Failed logins an address gets within `FAILED_LOGIN_WINDOW` before it is turned away.
+const MAX_FAILED_LOGINS: usize = 5;
+const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(60);
+
+fn check_username(username: &str) -> Result<(), String> {
+	if username.is_empty() || username.chars().count() > MAX_USERNAME {
+		return Err(format!("Usernames must be 1 to {} characters long", MAX_USERNAME));
+	}
+	if username.chars().any(|c| c.is_whitespace() || c.is_control()) {
+		return Err(String::from("Usernames must not contain whitespace"));
+	}
+	Ok(())
+}
+
+/// Checks a `Hello` against what this server speaks, returning the reason to reject it.
+fn check_hello(request: &ConnectionRequest) -> Result<(), String> {
+	if request.version < MIN_PROTOCOL_VERSION || request.version > PROTOCOL_VERSION {
+		return Err(format!(
+			"Unsupported protocol version {} (server speaks {} to {})",
+			request.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+		));
+	}
+	if request.public_key.as_ref().is_some_and(|key| key.len() > MAX_PUBLIC_KEY) {
+		return Err(String::from("Public key is too long"));
+	}
+	check_username(&request.username)
+}
+
+fn messages_fit(event: &ServerEvent) -> bool {
+	serde_json::to_vec(event).is_ok_and(|payload| payload.len() <= DEFAULT_MAX_FRAME_SIZE)
+}
+
+/// What a client waiting on the password worker will get once it is done.
+enum Awaiting {
+	Login(ConnectionRequest),
+	Register(String),
+}
+
+/// A client waiting on the password worker.
+struct Pending {
+	ip: IpAddr,
+	awaiting: Awaiting,
+	/// Frames that arrived meanwhile, handled once the password work is done.
+	held: Vec<Vec<u8>>,
+}
+
+/// Password work in progress and recent failed logins of one address.
+#[derive(Default)]
+struct LoginAttempts {
+	pending: usize,
+	failures: Vec<Instant>,
+}
+
+/// All state owned by the event loop.
+struct Server {
+	clients: HashMap<Token, Connection>,
+	/// Lowercased username of every welcomed client, so names are unique regardless of case.
+	usernames: HashMap<String, Token>,
+	roomlist: RoomList,
+	history: History,
+	accounts: Accounts,
+	moderation: Moderation,
+	/// Wraps every accepted connection in TLS when set.
+	tls: Option<Arc<ServerConfig>>,
+	settings: config::Server,
+	/// Reads the settings again from the config file and command line.
+	reload: Reload,
+	started: Instant,
+	metrics: Metrics,
+	/// Sends Argon2 work to the password worker.
+	passwords: mpsc::Sender<Job>,
+	/// Sends searches to the thread that reads through the logs.
+	searches: mpsc::Sender<Search>,
+	/// Clients with a search running; each gets one at a time.
+	searching: HashSet<Token>,
+	pending: HashMap<Token, Pending>,
+	logins: HashMap<IpAddr, LoginAttempts>,
+	/// Clients that had more to read when they used up their turn.
+	unread: HashSet<Token>,
+	/// Clients whose connection failed mid-handler, removed once it is done.
+	dying: HashMap<Token, FrameError>,
+	next_token: usize,
+}
+
+pub type Reload = Box<dyn Fn() -> Result<config::Server, String>>;
+
+impl Server {
+	/// Sets up the server, recreating every room that has a history log.
+	fn new(history: History, accounts: Accounts, moderation: Moderation, tls: Option<Arc<ServerConfig>>, settings: config::Server, reload: Reload, handle: Handle) -> io::Result<Server> {
+		let mut roomlist = RoomList::default();
+		for name in history.rooms()? {
+			// Carry on numbering where the log left off
+			roomlist.rooms.entry(name.clone()).or_default().seq = history.last_seq(&name)?;
+		}
+		// Rooms nobody wrote in yet still have their owner and bans
+		for name in moderation.rooms() {
+			roomlist.rooms.entry(name.to_string()).or_default();
+		}
+		let searches = history.spawn_searcher(handle.clone());
+
+		Ok(Server {
+			clients: HashMap::new(),
+			usernames: HashMap::new(),
+			roomlist,
+			history,
+			accounts,
+			moderation,
+			tls,
+			settings,
+			reload,
+			started: Instant::now(),
+			metrics: Metrics::default(),
+			passwords: accounts::spawn_worker(handle.clone()),
+			searches,
+			searching: HashSet::new(),
+			pending: HashMap::new(),
+			logins: HashMap::new(),
+			unread: HashSet::new(),
+			dying: HashMap::new(),
+			next_token: FIRST_CLIENT,
+		})
+	}
+
+	/// Accepts every pending connection on `listener`.
+	fn accept(&mut self, listener: &TcpListener, registry: &Registry) -> io::Result<()> {
+		loop {
+			let (mut stream, addr) = match listener.accept() {
+				Ok(accepted) => accepted,
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(err) => {
+					log!(Warn, "Failed to accept connection: {}", err);
+					return Ok(());
+				}
+			};
+			// Dual-stack listeners see IPv4 peers as IPv4-mapped IPv6 addresses
+			let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+			log!(Info, "Client connected! {}", addr);
+
+			let token = Token(self.next_token);
+			self.next_token += 1;
+			registry.register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+			let stream = match &self.tls {
+				Some(config) => match ServerConnection::new(config.clone()) {
+					Ok(conn) => Stream::Server(Box::new(StreamOwned::new(conn, stream))),
+					Err(err) => {
+						log!(Warn, "Failed to start TLS with {}: {}", addr, err);
+						continue;
+					}
+				},
+				None => Stream::Plain(stream),
+			};
+
+			self.clients.insert(token, Connection {
+				stream,
+				addr,
+				decoder: FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE),
+				encoder: FrameEncoder::new(DEFAULT_MAX_FRAME_SIZE),
+				username: String::new(),
+				welcomed: false,
+				capabilities: Vec::new(),
+				account: None,
+				public_key: None,
+				limiter: RateLimiter::new(self.settings.rate_limit.clone(), Instant::now()),
+			});
+			self.metrics.accepted += 1;
+			if self.settings.max_clients.is_some_and(|max| self.clients.len() > max) {
+				self.reject(token, String::from("The server is full, try again later"));
+			}
+		}
+	}
+
+	/// Drains everything readable from a client and handles each complete frame.
+	fn read(&mut self, token: Token) {
+		// Picked up again once its password work is done
+		if self.pending.contains_key(&token) || self.dying.contains_key(&token) {
+			return;
+		}
+		let mut frames = Vec::new();
+		let result = match self.clients.get_mut(&token) {
+			Some(con) => loop {
+				// The socket won't signal again for what is left, so come back for it
+				if frames.len() == MAX_FRAMES_PER_READ {
+					self.unread.insert(token);
+					break Ok(());
+				}
+				match con.decoder.read_frame(&mut con.stream) {
+					Ok(Some(frame)) => {
+						self.metrics.bytes_in += (HEADER_SIZE + frame.len()) as u64;
+						frames.push(frame);
+					}
+					Ok(None) => break Ok(()),
+					Err(err) => break Err(err),
+				}
+			},
+			None => return,
+		};
+
+		self.handle_frames(token, frames);
+
+		match result {
+			Ok(()) => (),
+			Err(err @ FrameError::TooLarge { .. }) => {
+				self.metrics.oversize_frames += 1;
+				if let Some(con) = self.clients.get(&token) {
+					log!(Info, "Closing connection with {}: {}", con.addr, err);
+				}
+				self.disconnect(token, &ServerEvent::ProtocolError { reason: err.to_string() });
+			}
+			Err(err) => self.close(token, err),
+		}
+	}
+
+	/// Gives the clients that were cut off last time another turn.
+	fn read_unread(&mut self) {
+		for token in mem::take(&mut self.unread) {
+			self.read(token);
+		}
+	}
+
+	/// Handles a client's frames in order, holding back the rest once one starts password work.
+	fn handle_frames(&mut self, token: Token, frames: Vec<Vec<u8>>) {
+		let mut frames = frames.into_iter();
+		while let Some(frame) = frames.next() {
+			if self.dying.contains_key(&token) {
+				return;
+			}
+			if let Some(pending) = self.pending.get_mut(&token) {
+				pending.held.push(frame);
+				pending.held.extend(frames);
+				return;
+			}
+			if !self.admit(token, frame.len()) {
+				continue;
+			}
+			match serde_json::from_slice::<ClientCommand>(&frame) {
+				Ok(command) => {
+					if !command.has_password() {
+						log!(Debug, "{}", String::from_utf8_lossy(&frame));
+					}
+					self.handle(token, command)
+				}
+				Err(err) => {
+					self.metrics.malformed_frames += 1;
+					log!(Warn, "Malformed command from {:?}: {}", token, err);
+				}
+			}
+		}
+	}
+
+	/// Hands password work to the worker, unless the client's address already has
+	/// too much going on, in which case the reason is returned.
+	fn submit(&mut self, token: Token, awaiting: Awaiting, job: Job) -> Result<(), String> {
+		let ip = match self.clients.get(&token) {
+			Some(con) => con.addr.ip(),
+			None => return Ok(()),
+		};
+		let attempts = self.logins.entry(ip).or_default();
+		attempts.failures.retain(|failed| failed.elapsed() < FAILED_LOGIN_WINDOW);
+		if attempts.failures.len() >= MAX_FAILED_LOGINS {
+			return Err(String::from("Too many failed logins from your address, try again in a minute"));
+		}
+		if attempts.pending >= MAX_PENDING_LOGINS {
+			return Err(String::from("Too many logins in progress from your address, try again shortly"));
+		}
+		attempts.pending += 1;
+		self.pending.insert(token, Pending { ip, awaiting, held: Vec::new() });
+		self.passwords.send(job).ok();
+		Ok(())
+	}
+
+	/// Finishes what a client was waiting on the password worker for, then
+	/// carries on with whatever it sent meanwhile.
+	fn password_done(&mut self, token: Token, outcome: Outcome) {
+		let pending = match self.pending.remove(&token) {
+			Some(pending) => pending,
+			None => return,
+		};
+		let attempts = self.logins.entry(pending.ip).or_default();
+		attempts.pending -= 1;
+		if let Outcome::Verified(false) = outcome {
+			attempts.failures.push(Instant::now());
+		}
+		if attempts.pending == 0 && attempts.failures.is_empty() {
+			self.logins.remove(&pending.ip);
+		}
+		if !self.clients.contains_key(&token) {
+			return;
+		}
+
+		match (pending.awaiting, outcome) {
+			(Awaiting::Login(request), Outcome::Verified(true)) => {
+				let account = request.username.to_lowercase();
+				self.welcome(token, request, Some(account));
+			}
+			(Awaiting::Login(request), _) => {
+				self.reject(token, format!("Wrong password for {}", request.username));
+				return;
+			}
+			(Awaiting::Register(username), Outcome::Hashed(Ok(hash))) => self.registered(token, username, hash),
+			(Awaiting::Register(_), outcome) => {
+				if let Outcome::Hashed(Err(err)) = outcome {
+					log!(Error, "{}", err);
+				}
+				self.send(token, &ServerEvent::Error { reason: String::from("Failed to register, try again later") });
+			}
+		}
+		self.handle_frames(token, pending.held);
+		self.read(token);
+	}
+
+	/// Charges a frame to the client's rate limit, telling it off if it is over.
+	/// Returns whether the frame should be handled.
+	fn admit(&mut self, token: Token, size: usize) -> bool {
+		let verdict = match self.clients.get_mut(&token) {
+			Some(con) => con.limiter.check(size, Instant::now()),
+			None => return false,
+		};
+		let action = match verdict {
+			Verdict::Allow => return true,
+			Verdict::Drop => return false,
+			Verdict::Warn => Throttle::Warning,
+			Verdict::Mute(duration) => Throttle::Mute { seconds: duration.as_secs() },
+			Verdict::Disconnect => Throttle::Disconnect,
+		};
+		if let Some(con) = self.clients.get(&token) {
+			match action {
+				Throttle::Warning => (),
+				Throttle::Mute { seconds } => log!(Warn, "Muting {} for {}s for flooding", con.addr, seconds),
+				Throttle::Disconnect => log!(Warn, "Disconnecting {} for flooding", con.addr),
+			}
+		}
+		let reason = String::from("You are sending too much too fast");
+		if let Throttle::Disconnect = action {
+			self.disconnect(token, &ServerEvent::Throttled { action, reason });
+		} else {
+			self.send(token, &ServerEvent::Throttled { action, reason });
+		}
+		false
+	}
+
+	/// Seconds left on a client's mute, if it is muted.
+	fn muted(&self, token: Token) -> Option<u64> {
+		let left = self.clients.get(&token)?.limiter.muted_for(Instant::now())?;
+		Some(left.as_secs().max(1))
+	}
+
+	/// Writes out whatever is still queued for a client.
+	fn flush(&mut self, token: Token) {
+		if let Some(con) = self.clients.get_mut(&token) {
+			if let Err(err) = con.encoder.flush_to(&mut con.stream) {
+				self.close(token, err);
+			}
+		}
+	}
+
+	/// Forgets a client everywhere the server tracks it and tells its rooms it quit.
+	fn remove(&mut self, token: Token) -> Option<Connection> {
+		let neighbours = self.neighbours(token);
+		self.roomlist.remove_user(token);
+		let con = self.clients.remove(&token)?;
+		if con.welcomed {
+			self.usernames.remove(&con.username.to_lowercase());
+			let event = ServerEvent::UserQuit { username: con.username.clone() };
+			for neighbour in neighbours {
+				self.send(neighbour, &event);
+			}
+		}
+		Some(con)
+	}
+
+	/// Carries out a command from the admin console.
+	fn admin(&mut self, command: Command) -> Result<Reply, String> {
+		match command {
+			Command::Rooms => {
+				let mut rooms: Vec<RoomInfo> = self.roomlist.rooms.iter().map(|(name, room)| RoomInfo {
+					name: name.clone(),
+					members: room.clients.len(),
+					owner: self.moderation.owner(name).map(String::from),
+				}).collect();
+				rooms.sort_by(|a, b| a.name.cmp(&b.name));
+				Ok(Reply::Rooms { rooms })
+			}
+			Command::Users => {
+				let mut users: Vec<UserInfo> = self.clients.iter().filter(|(_, con)| con.welcomed).map(|(token, con)| {
+					let mut rooms: Vec<String> = self.roomlist.rooms.iter()
+						.filter(|(_, room)| room.has_user(*token))
+						.map(|(name, _)| name.clone())
+						.collect();
+					rooms.sort();
+					UserInfo { username: con.username.clone(), addr: con.addr, account: con.account.clone(), rooms }
+				}).collect();
+				users.sort_by_key(|user| user.username.to_lowercase());
+				Ok(Reply::Users { users })
+			}
+			Command::Stats => Ok(Reply::Stats(Stats {
+				uptime: self.started.elapsed().as_secs(),
+				connections: self.clients.len(),
+				users: self.usernames.len(),
+				rooms: self.roomlist.rooms.len(),
+				messages: self.metrics.messages_in.values().sum(),
+			})),
+			Command::Kick { username, reason } => {
+				let token = *self.usernames.get(&username.to_lowercase()).ok_or_else(|| format!("User {} is not online", username))?;
+				let username = self.clients[&token].username.clone();
+				log!(Info, "Kicking {} off the server", username);
+				let mut text = String::from("You were kicked off the server");
+				if let Some(reason) = reason {
+					text += &format!(": {}", reason);
+				}
+				self.disconnect(token, &ServerEvent::Error { reason: text });
+				Ok(Reply::Done { message: format!("Kicked {}", username) })
+			}
+			Command::Ban { room, target, seconds, reason } => {
+				if !self.roomlist.rooms.contains_key(&room) {
+					return Err(format!("No such room: {}", room));
+				}
+				let until = sanction_end(seconds)?;
+				self.ban(None, ADMIN_SENDER.to_string(), &room, &target, until, reason);
+				Ok(Reply::Done { message: format!("Banned {} from {}", target, room) })
+			}
+			Command::Broadcast { content } => {
+				let tokens: Vec<Token> = self.clients.iter().filter(|(_, con)| con.welcomed).map(|(token, _)| *token).collect();
+				log!(Info, "Broadcasting to {} clients: {}", tokens.len(), content);
+				let event = ServerEvent::Notice { content };
+				for token in &tokens {
+					self.send(*token, &event);
+				}
+				Ok(Reply::Done { message: format!("Sent to {} users", tokens.len()) })
+			}
+			Command::Say { room, content } => {
+				if content.len() > MAX_CONTENT_SIZE {
+					return Err(format!("Messages are limited to {} bytes", MAX_CONTENT_SIZE));
+				}
+				let seq = match self.roomlist.rooms.get_mut(&room) {
+					Some(r) => r.next_seq(),
+					None => return Err(format!("No such room: {}", room)),
+				};
+				self.post(Msg { content, sender: ADMIN_SENDER.to_string(), room: room.clone(), seq, ..Msg::default() });
+				Ok(Reply::Done { message: format!("Said in {}", room) })
+			}
+			Command::SetRole { room, username, role } => {
+				if !self.roomlist.rooms.contains_key(&room) {
+					return Err(format!("No such room: {}", room));
+				}
+				let account = username.to_lowercase();
+				if !self.accounts.is_registered(&account) {
+					return Err(format!("{} has no account, only registered users hold roles", username));
+				}
+				let username = self.usernames.get(&account).map_or(username, |t| self.clients[t].username.clone());
+				log!(Info, "{} made {} {} of {}", ADMIN_SENDER, username, role, room);
+				self.moderation.set_role(&room, &account, role);
+				let message = format!("Made {} {} of {}", username, role, room);
+				self.broadcast_room(&room, &ServerEvent::RoleChanged { room: room.clone(), username, role, by: ADMIN_SENDER.to_string() });
+				Ok(Reply::Done { message })
+			}
+			Command::Reload => self.reload(),
+		}
+	}
+
+	/// Applies the settings read again from the config file. Rate limits only
+	/// change for clients connecting from now on.
+	fn reload(&mut self) -> Result<Reply, String> {
+		let mut settings = (self.reload)()?;
+		// Listeners and data files are set up once, at startup
+		let mut pending = Vec::new();
+		macro_rules! keep {
+			($($field:ident),*) => {$(
+				if settings.$field != self.settings.$field {
+					pending.push(stringify!($field));
+					settings.$field = self.settings.$field.clone();
+				}
+			)*};
+		}
+		keep!(bind, port, history_dir, history_backlog, history_max_file_size, user_db, moderation_db, control_socket, metrics);
+
+		crate::log::set_level(settings.log_level);
+		self.accounts.set_required(settings.require_auth);
+		self.settings = settings;
+		log!(Info, "Reloaded settings");
+		let message = if pending.is_empty() {
+			String::from("Reloaded settings")
+		} else {
+			format!("Reloaded settings; {} only change on restart", pending.join(", "))
+		};
+		Ok(Reply::Done { message })
+	}
+
+	/// The metrics endpoint's page: the counters, and gauges read off the current state.
+	fn metrics(&self) -> String {
+		let mut members: Vec<(&str, usize)> = self.roomlist.rooms.iter().map(|(name, room)| (name.as_str(), room.clients.len())).collect();
+		members.sort();
+		self.metrics.render(&Gauges {
+			uptime: self.started.elapsed(),
+			connections: self.clients.len(),
+			users: self.usernames.len(),
+			members,
+		})
+	}
+
+	/// Tells every client that the server is going away.
+	fn shutdown(&mut self, reason: Option<String>, restart_in: Option<u64>) {
+		log!(Info, "Shutting down, waiting up to {}s for {} clients to leave", self.settings.shutdown_timeout, self.clients.len());
+		let event = ServerEvent::Shutdown { reason, restart_in };
+		let tokens: Vec<Token> = self.clients.keys().copied().collect();
+		for token in tokens {
+			self.send(token, &event);
+		}
+	}
+
+	/// Closes the connections still left after a shutdown and flushes the history logs.
+	fn finish(&mut self) {
+		let tokens: Vec<Token> = self.clients.keys().copied().collect();
+		for token in tokens {
+			if let Some(mut con) = self.remove(token) {
+				con.stream.close_notify();
+				con.stream.get_ref().shutdown(Shutdown::Both).ok();
+			}
+		}
+		if let Err(err) = self.history.sync() {
+			log!(Error, "Failed to flush history logs: {}", err);
+		}
+		log!(Info, "Server stopped");
+	}
+
+	fn close(&mut self, token: Token, reason: FrameError) {
+		if let Some(con) = self.remove(token) {
+			match reason {
+				FrameError::Closed => log!(Info, "Closing connection with {}", con.addr),
+				err => log!(Info, "Closing connection with {}: {}", con.addr, err),
+			}
+		}
+	}
+
+	/// Queues `event` for a client. A failed write only marks the client dying, so
+	/// handlers never find it gone halfway through; `reap` removes it afterwards.
+	fn send(&mut self, token: Token, event: &ServerEvent) {
+		if self.dying.contains_key(&token) {
+			return;
+		}
+		let outbound = serde_json::to_vec(event).unwrap();
+		if let Some(con) = self.clients.get_mut(&token) {
+			// Our own reply being too big is no reason to drop the client
+			if let Err(err) = con.encoder.push(&outbound) {
+				log!(Error, "Not sending {} a reply: {}", con.addr, err);
+				return;
+			}
+			self.metrics.bytes_out += (HEADER_SIZE + outbound.len()) as u64;
+			match con.encoder.flush_to(&mut con.stream) {
+				Ok(_) if con.encoder.queued() > MAX_QUEUED => {
+					let err = io::Error::other(format!("fell {} bytes behind", con.encoder.queued()));
+					self.dying.insert(token, FrameError::Io(err));
+				}
+				Ok(_) => (),
+				Err(err) => {
+					self.dying.insert(token, err);
+				}
+			}
+		}
+	}
+
+	/// Closes the clients that died while being handled, and any that die telling their rooms.
+	fn reap(&mut self) {
+		while !self.dying.is_empty() {
+			for (token, err) in mem::take(&mut self.dying) {
+				self.close(token, err);
+			}
+		}
+	}
+
+	/// Sends a list of names in pages of `LIST_PAGE`, always at least one.
+	fn send_pages<F: Fn(Vec<String>, bool) -> ServerEvent>(&mut self, token: Token, names: Vec<String>, page: F) {
+		let pages: Vec<&[String]> = names.chunks(LIST_PAGE).collect();
+		if pages.is_empty() {
+			self.send(token, &page(Vec::new(), false));
+		}
+		for (i, names) in pages.iter().enumerate() {
+			self.send(token, &page(names.to_vec(), i + 1 < pages.len()));
+		}
+	}
+
+	/// Logs a message to its room's history and delivers it to the room.
+	fn post(&mut self, msg: Msg) {
+		*self.metrics.messages_in.entry(msg.room.clone()).or_default() += 1;
+		if let Err(err) = self.history.append(&msg) {
+			log!(Error, "Failed to log message to {}: {}", msg.room, err);
+		}
+		let room = msg.room.clone();
+		self.broadcast_room(&room, &ServerEvent::Message(msg));
+		let members = self.roomlist.rooms.get(&room).map_or(0, |r| r.clients.len() as u64);
+		*self.metrics.messages_out.entry(room).or_default() += members;
+	}
+
+	/// Sends `event` to every member of `room`.
+	fn broadcast_room(&mut self, room: &str, event: &ServerEvent) {
+		let members = match self.roomlist.rooms.get(room) {
+			Some(r) => r.clients.clone(),
+			None => return,
+		};
+		let started = Instant::now();
+		for token in members {
+			self.send(token, event);
+		}
+		self.metrics.broadcast_latency.observe(started.elapsed());
+	}
+
+	/// Everyone other than `token` who shares at least one room with it.
+	fn neighbours(&self, token: Token) -> Vec<Token> {
+		let mut neighbours = Vec::new();
+		for room in self.roomlist.rooms.values() {
+			if room.has_user(token) {
+				neighbours.extend(room.clients.iter().copied().filter(|t| *t != token));
+			}
+		}
+		neighbours.sort();
+		neighbours.dedup();
+		neighbours
+	}
+
+	/// Sends `event` once to the client and to everyone sharing a room with it.
+	fn broadcast_neighbours(&mut self, token: Token, event: &ServerEvent) {
+		self.send(token, event);
+		for neighbour in self.neighbours(token) {
+			self.send(neighbour, event);
+		}
+	}
+
+	fn members(&self, room: &str) -> Vec<String> {
+		let mut users: Vec<String> = match self.roomlist.rooms.get(room) {
+			Some(r) => r.clients.iter().filter_map(|t| self.clients.get(t)).map(|con| con.username.clone()).collect(),
+			None => Vec::new(),
+		};
+		users.sort_by_key(|u| u.to_lowercase());
+		users
+	}
+
+	/// Why a client is kept out of a room, if it is banned from it.
+	fn banned_from(&self, token: Token, room: &str) -> Option<String> {
+		// Bans never keep out operators, so nobody locks themselves out by address
+		if self.role(token, room) >= Role::Operator {
+			return None;
+		}
+		let ban = self.moderation.banned(room, &self.name(token), self.clients.get(&token)?.addr.ip())?;
+		Some(format!("You are banned from {}{}", room, until_text(ban.until)))
+	}
+
+	fn join(&mut self, token: Token, room: String) {
+		if let Some(reason) = self.banned_from(token, &room) {
+			self.send(token, &ServerEvent::Error { reason });
+			return;
+		}
+		let already_in = match self.roomlist.rooms.get_mut(&room) {
+			Some(r) => {
+				let already_in = r.has_user(token);
+				r.add_user(token);
+				already_in
+			}
+			None => {
+				self.send(token, &ServerEvent::Error { reason: format!("No such room: {}", room) });
+				return;
+			}
+		};
+
+		if !already_in {
+			let username = self.clients[&token].username.clone();
+			let event = ServerEvent::UserJoined { room: room.clone(), username };
+			for member in self.roomlist.rooms[&room].clients.clone() {
+				if member != token {
+					self.send(member, &event);
+				}
+			}
+		}
+		let users = self.members(&room);
+		self.send(token, &ServerEvent::Joined { room: room.clone() });
+		if self.clients.get(&token).is_some_and(|con| con.capabilities.contains(&Capability::History)) {
+			self.send_backlog(token, &room);
+		}
+		self.send_pages(token, users, |users, more| ServerEvent::Members { room: room.clone(), users, more });
+	}
+
+	/// Sends the newest logged messages of a room.
+	fn send_backlog(&mut self, token: Token, room: &str) {
+		let messages = match self.history.last(room, self.history.backlog_size()) {
+			Ok(messages) => messages,
+			Err(err) => {
+				log!(Error, "Failed to read history of {}: {}", room, err);
+				return;
+			}
+		};
+		if !messages.is_empty() {
+			self.send_messages(token, messages, |messages| ServerEvent::Backlog { room: room.to_string(), messages });
+		}
+	}
+
+	/// Sends the event `build` makes from `messages`, dropping the oldest ones if they don't fit in a frame.
+	fn send_messages<F: Fn(Vec<Msg>) -> ServerEvent>(&mut self, token: Token, mut messages: Vec<Msg>, build: F) {
+		loop {
+			let event = build(messages.clone());
+			if messages.is_empty() || messages_fit(&event) {
+				self.send(token, &event);
+				return;
+			}
+			messages.remove(0);
+		}
+	}
+
+	/// Runs a query against a room's log on behalf of one of its members.
+	fn query_history<Q, F>(&mut self, token: Token, room: &str, query: Q, build: F)
+	where
+		Q: FnOnce(&History) -> io::Result<Vec<Msg>>,
+		F: Fn(Vec<Msg>) -> ServerEvent,
+	{
+		if !self.roomlist.rooms.get(room).is_some_and(|r| r.has_user(token)) {
+			self.send(token, &ServerEvent::Error { reason: format!("You are not in room {}", room) });
+			return;
+		}
+		match query(&self.history) {
+			Ok(messages) => self.send_messages(token, messages, build),
+			Err(err) => {
+				log!(Error, "Failed to read history of {}: {}", room, err);
+				self.send(token, &ServerEvent::Error { reason: format!("History of {} is unavailable", room) });
+			}
+		}
+	}
+
+	/// Sends a client the results of its search once the search thread is done.
+	fn searched(&mut self, search: Search, results: io::Result<Vec<Msg>>) {
+		self.searching.remove(&search.token);
+		let Search { token, room, query, .. } = search;
+		match results {
+			Ok(results) => self.send_messages(token, results, |results| {
+				ServerEvent::SearchResults { room: room.clone(), query: query.clone(), results }
+			}),
+			Err(err) => {
+				log!(Error, "Failed to search history of {}: {}", room, err);
+				self.send(token, &ServerEvent::Error { reason: format!("History of {} is unavailable", room) });
+			}
+		}
+	}
+
+	/// Name bans and mutes are checked against: the account if logged in, the username otherwise.
+	fn name(&self, token: Token) -> String {
+		match self.clients.get(&token) {
+			Some(con) => con.account.clone().unwrap_or_else(|| con.username.to_lowercase()),
+			None => String::new(),
+		}
+	}
+
+	/// What sanctions against a client are kept under: its account, or its
+	/// address if it has none, since a new username would shake off anything else.
+	fn identity(&self, token: Token) -> Target {
+		match self.clients.get(&token) {
+			Some(con) => match &con.account {
+				Some(account) => Target::Name(account.clone()),
+				None => Target::Ip(con.addr.ip()),
+			},
+			None => Target::Name(String::new()),
+		}
+	}
+
+	/// Identity of a user who may be offline, and their connection if they are online.
+	fn resolve(&self, username: &str) -> (Target, Option<Token>) {
+		match self.usernames.get(&username.to_lowercase()) {
+			Some(token) => (self.identity(*token), Some(*token)),
+			None => (Target::Name(username.to_lowercase()), None),
+		}
+	}
+
+	/// A client's role in a room; only accounts hold any.
+	fn role(&self, token: Token, room: &str) -> Role {
+		self.role_of(room, &self.identity(token))
+	}
+
+	fn role_of(&self, room: &str, target: &Target) -> Role {
+		match target {
+			Target::Name(account) => self.moderation.role(room, account),
+			Target::Ip(_) => Role::Member,
+		}
+	}
+
+	/// Checks that a client is an operator of `room` and, if `target` is given,
+	/// outranks that user there. Tells the client why not otherwise.
+	fn may_moderate(&mut self, token: Token, room: &str, target: Option<&Target>) -> bool {
+		if !self.roomlist.rooms.contains_key(room) {
+			self.send(token, &ServerEvent::Error { reason: format!("No such room: {}", room) });
+			return false;
+		}
+		let own = self.role(token, room);
+		if own < Role::Operator {
+			self.send(token, &ServerEvent::Error { reason: format!("You are not an operator of {}", room) });
+			return false;
+		}
+		if let Some(target) = target {
+			let theirs = self.role_of(room, target);
+			if theirs >= own {
+				let reason = format!("You can't moderate {}, who is {} of {}", target, theirs, room);
+				self.send(token, &ServerEvent::Error { reason });
+				return false;
+			}
+		}
+		true
+	}
+
+	/// Tells a room, and the moderator if they are elsewhere, what a moderator did.
+	fn announce(&mut self, token: Token, room: &str, event: &ServerEvent) {
+		self.broadcast_room(room, event);
+		if !self.roomlist.rooms.get(room).is_some_and(|r| r.has_user(token)) {
+			self.send(token, event);
+		}
+	}
+
+	/// Creates an account once the worker has hashed its password.
+	fn registered(&mut self, token: Token, username: String, hash: String) {
+		if let Err(reason) = self.accounts.register(&username, hash) {
+			self.send(token, &ServerEvent::Error { reason });
+			return;
+		}
+		log!(Info, "Registered account {}", username);
+		if let Some(con) = self.clients.get_mut(&token) {
+			con.account = Some(username.to_lowercase());
+		}
+		self.send(token, &ServerEvent::Registered { username });
+		// Clients held back by a server that requires accounts get put in a room now
+		if !self.roomlist.rooms.values().any(|r| r.has_user(token)) {
+			self.join(token, String::from(DEFAULT_ROOM));
+		}
+	}
+
+	/// Finishes a handshake, once the password of an account has been checked.
+	fn welcome(&mut self, token: Token, request: ConnectionRequest, account: Option<String>) {
+		// Somebody may have taken the name while the password was checked
+		if self.usernames.contains_key(&request.username.to_lowercase()) {
+			self.reject(token, format!("Username {} is already taken", request.username));
+			return;
+		}
+		self.usernames.insert(request.username.to_lowercase(), token);
+		let capabilities = protocol::negotiate(&request.capabilities);
+		if let Some(con) = self.clients.get_mut(&token) {
+			con.username = request.username;
+			con.welcomed = true;
+			con.capabilities = capabilities.clone();
+			con.account = account;
+			con.public_key = request.public_key;
+		}
+		self.send(token, &ServerEvent::Welcome {
+			version: PROTOCOL_VERSION,
+			capabilities,
+		});
+		let motd: Vec<String> = self.settings.motd.iter().flat_map(|motd| motd.lines()).map(String::from).collect();
+		for content in motd {
+			self.send(token, &ServerEvent::Notice { content });
+		}
+		if !self.authenticated(token) {
+			self.send(token, &ServerEvent::Notice {
+				content: String::from("This server requires an account, /register <password> before joining a room"),
+			});
+			return;
+		}
+		let room = if !self.roomlist.rooms.contains_key(&request.room) {
+			self.send(token, &ServerEvent::Notice { content: format!("Room {} no longer exists", request.room) });
+			String::from(DEFAULT_ROOM)
+		} else if let Some(content) = self.banned_from(token, &request.room) {
+			self.send(token, &ServerEvent::Notice { content });
+			String::from(DEFAULT_ROOM)
+		} else {
+			request.room
+		};
+		self.join(token, room);
+	}
+
+	/// Bans a user or address from a room and takes whoever it catches out of it.
+	/// The moderator, if a client, hears of it even from outside the room.
+	fn ban(&mut self, moderator: Option<Token>, by: String, room: &str, target: &str, until: Option<DateTime<Utc>>, reason: Option<String>) {
+		match Target::parse(target) {
+			Target::Ip(ip) => {
+				log!(Info, "{} banned {} from {}", by, ip, room);
+				self.moderation.ban(room, Sanction { target: Target::Ip(ip), username: None, by: by.clone(), reason: reason.clone(), until });
+				// Only the moderator learns the address; the room sees who got kicked
+				if let Some(token) = moderator {
+					self.send(token, &ServerEvent::Moderation {
+						room: room.to_string(),
+						action: ModAction::Banned,
+						target: ip.to_string(),
+						by: by.clone(),
+						reason: reason.clone(),
+						until,
+					});
+				}
+				let caught: Vec<Token> = self.roomlist.rooms[room].clients.iter().copied()
+					.filter(|t| self.clients.get(t).is_some_and(|con| con.addr.ip() == ip))
+					.filter(|t| self.role(*t, room) < Role::Operator)
+					.collect();
+				for target in caught {
+					let event = ServerEvent::Moderation {
+						room: room.to_string(),
+						action: ModAction::Kicked,
+						target: self.clients[&target].username.clone(),
+						by: by.clone(),
+						reason: reason.clone(),
+						until: None,
+					};
+					match moderator {
+						Some(token) => self.announce(token, room, &event),
+						None => self.broadcast_room(room, &event),
+					}
+					self.eject(target, room);
+				}
+			}
+			Target::Name(_) => {
+				let (identity, online) = self.resolve(target);
+				let target_name = online.map_or(target.to_string(), |t| self.clients[&t].username.clone());
+				log!(Info, "{} banned {} from {}", by, target_name, room);
+				let username = Some(target_name.to_lowercase());
+				self.moderation.ban(room, Sanction { target: identity, username, by: by.clone(), reason: reason.clone(), until });
+				let event = ServerEvent::Moderation {
+					room: room.to_string(),
+					action: ModAction::Banned,
+					target: target_name,
+					by,
+					reason,
+					until,
+				};
+				match moderator {
+					Some(token) => self.announce(token, room, &event),
+					None => self.broadcast_room(room, &event),
+				}
+				if let Some(target) = online.filter(|t| self.roomlist.rooms[room].has_user(*t)) {
+					self.eject(target, room);
+				}
+			}
+		}
+	}
+
+	/// Takes a client out of a room after a moderator removed it.
+	fn eject(&mut self, target: Token, room: &str) {
+		if let Some(r) = self.roomlist.rooms.get_mut(room) {
+			r.remove_user(target);
+		}
+		self.send(target, &ServerEvent::Left { room: room.to_string() });
+	}
+
+	/// Whether a client may join rooms: it has logged in, or the server doesn't require it.
+	fn authenticated(&self, token: Token) -> bool {
+		!self.accounts.required() || self.clients.get(&token).is_some_and(|con| con.account.is_some())
+	}
+
+	/// Sends a `Rejected` event and closes the connection.
+	fn reject(&mut self, token: Token, reason: String) {
+		if let Some(con) = self.clients.get(&token) {
+			log!(Info, "Rejecting {}: {}", con.addr, reason);
+		}
+		self.metrics.rejected += 1;
+		self.disconnect(token, &ServerEvent::Rejected { reason });
+	}
+
+	/// Sends a last event and closes the connection.
+	fn disconnect(&mut self, token: Token, event: &ServerEvent) {
+		if let Some(mut con) = self.remove(token) {
+			let outbound = serde_json::to_vec(event).unwrap();
+			if con.encoder.push(&outbound).is_ok() {
+				self.metrics.bytes_out += (HEADER_SIZE + outbound.len()) as u64;
+				con.encoder.flush_to(&mut con.stream).ok();
+			}
+			con.stream.close_notify();
+			con.stream.get_ref().shutdown(Shutdown::Both).ok();
+		}
+	}
+
+	fn handle(&mut self, token: Token, command: ClientCommand) {
+		let welcomed = match self.clients.get(&token) {
+			Some(con) => con.welcomed,
+			// Closed while earlier frames of the same read were handled
+			None => return,
+		};
+
+		match command {
+			ClientCommand::Hello(request) => {
+				if welcomed {
+					self.send(token, &ServerEvent::Error { reason: String::from("Already connected") });
+					return;
+				}
+				if let Err(reason) = check_hello(&request) {
+					self.reject(token, reason);
+					return;
+				}
+				if self.usernames.contains_key(&request.username.to_lowercase()) {
+					self.reject(token, format!("Username {} is already taken", request.username));
+					return;
+				}
+				match (request.password.clone(), self.accounts.hash(&request.username)) {
+					(Some(password), Some(hash)) => {
+						let job = Job::Verify { token, hash, password };
+						if let Err(reason) = self.submit(token, Awaiting::Login(request), job) {
+							self.reject(token, reason);
 						}
 					}
-					Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-					Err(_) => {
-						println!("Closing connection with {}", addr);
-						break;
+					(Some(_), None) => {
+						self.reject(token, format!("No account named {}, connect without a password and /register", request.username));
 					}
+					(None, Some(_)) => {
+						self.reject(token, format!("Username {} is registered, log in with its password", request.username));
+					}
+					(None, None) => self.welcome(token, request, None),
+				}
+			}
+			_ if !welcomed => self.reject(token, String::from("Expected a hello before any other command")),
+			ClientCommand::Message { .. } | ClientCommand::Direct { .. } if self.muted(token).is_some() => {
+				let reason = format!("You are muted for {} more seconds", self.muted(token).unwrap_or_default());
+				self.send(token, &ServerEvent::Error { reason });
+			}
+			ClientCommand::Message { content, .. } if content.len() > MAX_CONTENT_SIZE => {
+				let reason = format!("Messages are limited to {} bytes", MAX_CONTENT_SIZE);
+				self.send(token, &ServerEvent::Error { reason });
+			}
+			ClientCommand::Direct { sealed, .. } if sealed.ciphertext.len() > MAX_SEALED_SIZE => {
+				let reason = format!("Messages are limited to {} bytes", MAX_CONTENT_SIZE);
+				self.send(token, &ServerEvent::Error { reason });
+			}
+			ClientCommand::Message { room, content, color } => {
+				if let Some(mute) = self.moderation.muted(&room, &self.name(token), self.clients[&token].addr.ip()) {
+					let reason = format!("You are muted in {}{}", room, until_text(mute.until));
+					self.send(token, &ServerEvent::Error { reason });
+					return;
 				}
+				let seq = match self.roomlist.rooms.get_mut(&room) {
+					Some(r) if r.has_user(token) => r.next_seq(),
+					_ => {
+						self.send(token, &ServerEvent::Error { reason: format!("You are not in room {}", room) });
+						return;
+					}
+				};
+				// Identity, time and ordering come from the server, never from the client
+				self.post(Msg {
+					content,
+					sender: self.clients[&token].username.clone(),
+					color,
+					timestamp: Utc::now(),
+					room,
+					seq,
+				});
 			}
-			Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-    		Err(_) => {
-				println!("Closing connection with {}", addr);
-				break;
+			ClientCommand::Direct { to, sealed, color } => {
+				let recipient = match self.usernames.get(&to.to_lowercase()) {
+					Some(recipient) => *recipient,
+					None => {
+						self.send(token, &ServerEvent::Error { reason: format!("User {} is not online", to) });
+						return;
+					}
+				};
+				let sender = &self.clients[&token];
+				let sender_key = match &sender.public_key {
+					Some(key) => key.clone(),
+					None => {
+						self.send(token, &ServerEvent::Error { reason: String::from("Publish a key in the handshake to send direct messages") });
+						return;
+					}
+				};
+				let msg = DirectMsg {
+					sealed,
+					sender: sender.username.clone(),
+					sender_key,
+					recipient: self.clients[&recipient].username.clone(),
+					color,
+					timestamp: Utc::now(),
+				};
+				let event = ServerEvent::Direct(msg);
+				self.send(recipient, &event);
+				// The sender's copy confirms delivery
+				if recipient != token {
+					self.send(token, &event);
+				}
+			}
+			ClientCommand::GetKey { username } => {
+				let (username, key) = match self.usernames.get(&username.to_lowercase()) {
+					Some(owner) => {
+						let con = &self.clients[owner];
+						(con.username.clone(), con.public_key.clone())
+					}
+					None => (username, None),
+				};
+				self.send(token, &ServerEvent::PublicKey { username, key });
+			}
+			ClientCommand::Nick { username } => {
+				if let Err(reason) = check_username(&username) {
+					self.send(token, &ServerEvent::Error { reason });
+					return;
+				}
+				// A new name mustn't shake off a ban or mute
+				if self.moderation.sanctioned(&self.name(token), self.clients[&token].addr.ip()) {
+					self.send(token, &ServerEvent::Error { reason: String::from("You can't change your name while banned or muted") });
+					return;
+				}
+				let old = self.clients[&token].username.clone();
+				let key = username.to_lowercase();
+				if self.accounts.is_registered(&username) && self.clients[&token].account.as_ref() != Some(&key) {
+					self.send(token, &ServerEvent::Error { reason: format!("Username {} is registered", username) });
+					return;
+				}
+				// Changing only the case of your own name is allowed
+				if self.usernames.get(&key).is_some_and(|owner| *owner != token) {
+					self.send(token, &ServerEvent::Error { reason: format!("Username {} is already taken", username) });
+					return;
+				}
+				self.usernames.remove(&old.to_lowercase());
+				self.usernames.insert(key, token);
+				if let Some(con) = self.clients.get_mut(&token) {
+					con.username = username.clone();
+				}
+				self.broadcast_neighbours(token, &ServerEvent::NickChanged { old, new: username });
+			}
+			ClientCommand::Register { password } => {
+				let con = &self.clients[&token];
+				if let Some(account) = &con.account {
+					let reason = format!("You are already logged in to {}", account);
+					self.send(token, &ServerEvent::Error { reason });
+					return;
+				}
+				let username = con.username.clone();
+				if let Err(reason) = self.accounts.check_new(&username, &password) {
+					self.send(token, &ServerEvent::Error { reason });
+					return;
+				}
+				if let Err(reason) = self.submit(token, Awaiting::Register(username), Job::Hash { token, password }) {
+					self.send(token, &ServerEvent::Error { reason });
+				}
+			}
+			ClientCommand::CreateRoom { .. } | ClientCommand::Join { .. } if !self.authenticated(token) => {
+				self.send(token, &ServerEvent::Error { reason: String::from("Log in or /register before joining a room") });
+			}
+			ClientCommand::CreateRoom { room } => match self.roomlist.create(&room) {
+				Ok(()) => {
+					let owner = self.clients[&token].account.clone();
+					self.moderation.create(&room, owner.as_deref());
+					if owner.is_none() {
+						self.send(token, &ServerEvent::Notice { content: format!("Nobody moderates {}; log in to own the rooms you create", room) });
+					}
+					self.join(token, room);
+				}
+				Err(reason) => self.send(token, &ServerEvent::Error { reason }),
+			},
+			ClientCommand::Join { room } => self.join(token, room),
+			ClientCommand::Leave { room } => {
+				match self.roomlist.rooms.get_mut(&room) {
+					Some(r) if r.has_user(token) => r.remove_user(token),
+					_ => {
+						self.send(token, &ServerEvent::Error { reason: format!("You are not in room {}", room) });
+						return;
+					}
+				}
+				let username = self.clients[&token].username.clone();
+				self.broadcast_room(&room, &ServerEvent::UserLeft { room: room.clone(), username });
+				self.send(token, &ServerEvent::Left { room });
+			}
+			ClientCommand::ListRooms => {
+				let rooms = self.roomlist.names();
+				self.send_pages(token, rooms, |rooms, more| ServerEvent::Rooms { rooms, more });
+			}
+			ClientCommand::History { room, before, limit } => {
+				let limit = limit.min(MAX_HISTORY_PAGE);
+				self.query_history(token, &room, |history| history.before(&room, before, limit), |messages| {
+					ServerEvent::HistoryPage { room: room.clone(), messages }
+				});
+			}
+			ClientCommand::Search { room, query } => {
+				if !self.roomlist.rooms.get(&room).is_some_and(|r| r.has_user(token)) {
+					self.send(token, &ServerEvent::Error { reason: format!("You are not in room {}", room) });
+					return;
+				}
+				if !self.searching.insert(token) {
+					self.send(token, &ServerEvent::Error { reason: String::from("Wait for your last search to finish") });
+					return;
+				}
+				self.searches.send(Search { token, room, query, limit: MAX_SEARCH_RESULTS }).ok();
+			}
+			ClientCommand::Context { room, seq } => {
+				let before = seq.saturating_add(CONTEXT_SIZE + 1);
+				let count = (CONTEXT_SIZE * 2 + 1) as usize;
+				self.query_history(token, &room, |history| history.before(&room, before, count), |messages| {
+					ServerEvent::Context { room: room.clone(), seq, messages }
+				});
+			}
+			ClientCommand::Who { room } => {
+				if !self.roomlist.rooms.contains_key(&room) {
+					self.send(token, &ServerEvent::Error { reason: format!("No such room: {}", room) });
+					return;
+				}
+				let users = self.members(&room);
+				self.send_pages(token, users, |users, more| ServerEvent::Members { room: room.clone(), users, more });
+			}
+			ClientCommand::Kick { room, username, reason } => {
+				let target = match self.usernames.get(&username.to_lowercase()) {
+					Some(target) if self.roomlist.rooms.get(&room).is_some_and(|r| r.has_user(*target)) => *target,
+					_ => {
+						self.send(token, &ServerEvent::Error { reason: format!("{} is not in room {}", username, room) });
+						return;
+					}
+				};
+				if !self.may_moderate(token, &room, Some(&self.identity(target))) {
+					return;
+				}
+				let by = self.clients[&token].username.clone();
+				let target_name = self.clients[&target].username.clone();
+				log!(Info, "{} kicked {} from {}", by, target_name, room);
+				self.announce(token, &room, &ServerEvent::Moderation {
+					room: room.clone(),
+					action: ModAction::Kicked,
+					target: target_name,
+					by,
+					reason,
+					until: None,
+				});
+				self.eject(target, &room);
+			}
+			ClientCommand::Ban { room, target, seconds, reason } => {
+				// Addresses have no role to outrank
+				let identity = match Target::parse(&target) {
+					Target::Ip(_) => None,
+					Target::Name(_) => Some(self.resolve(&target).0),
+				};
+				if !self.may_moderate(token, &room, identity.as_ref()) {
+					return;
+				}
+				let until = match sanction_end(seconds) {
+					Ok(until) => until,
+					Err(reason) => {
+						self.send(token, &ServerEvent::Error { reason });
+						return;
+					}
+				};
+				let by = self.clients[&token].username.clone();
+				self.ban(Some(token), by, &room, &target, until, reason);
+			}
+			ClientCommand::Unban { room, target } => {
+				if !self.may_moderate(token, &room, None) {
+					return;
+				}
+				let parsed = Target::parse(&target);
+				// Only the moderator learns an address
+				let by_address = matches!(parsed, Target::Ip(_));
+				let lifted = match parsed {
+					// The user's current identity, or the name the ban was issued under
+					Target::Name(name) => {
+						let identity = self.resolve(&target).0;
+						self.moderation.unban(&room, &identity) | self.moderation.unban(&room, &Target::Name(name))
+					}
+					ip => self.moderation.unban(&room, &ip),
+				};
+				if !lifted {
+					self.send(token, &ServerEvent::Error { reason: format!("{} is not banned from {}", target, room) });
+					return;
+				}
+				let event = ServerEvent::Moderation {
+					room: room.clone(),
+					action: ModAction::Unbanned,
+					target,
+					by: self.clients[&token].username.clone(),
+					reason: None,
+					until: None,
+				};
+				if by_address {
+					self.send(token, &event);
+				} else {
+					self.announce(token, &room, &event);
+				}
+			}
+			ClientCommand::Mute { room, username, seconds, reason } => {
+				let (identity, online) = self.resolve(&username);
+				if !self.may_moderate(token, &room, Some(&identity)) {
+					return;
+				}
+				let until = match sanction_end(seconds) {
+					Ok(until) => until,
+					Err(reason) => {
+						self.send(token, &ServerEvent::Error { reason });
+						return;
+					}
+				};
+				let by = self.clients[&token].username.clone();
+				let target = online.map_or(username, |t| self.clients[&t].username.clone());
+				log!(Info, "{} muted {} in {}", by, target, room);
+				let name = Some(target.to_lowercase());
+				self.moderation.mute(&room, Sanction { target: identity, username: name, by: by.clone(), reason: reason.clone(), until });
+				self.announce(token, &room, &ServerEvent::Moderation { room: room.clone(), action: ModAction::Muted, target, by, reason, until });
+			}
+			ClientCommand::Unmute { room, username } => {
+				let (identity, online) = self.resolve(&username);
+				if !self.may_moderate(token, &room, None) {
+					return;
+				}
+				let name = Target::Name(username.to_lowercase());
+				if !(self.moderation.unmute(&room, &identity) | self.moderation.unmute(&room, &name)) {
+					self.send(token, &ServerEvent::Error { reason: format!("{} is not muted in {}", username, room) });
+					return;
+				}
+				let target = online.map_or(username, |t| self.clients[&t].username.clone());
+				let by = self.clients[&token].username.clone();
+				self.announce(token, &room, &ServerEvent::Moderation { room: room.clone(), action: ModAction::Unmuted, target, by, reason: None, until: None });
+			}
+			ClientCommand::SetRole { room, username, role } => {
+				if self.role(token, &room) != Role::Owner {
+					self.send(token, &ServerEvent::Error { reason: format!("Only the owner of {} can change roles", room) });
+					return;
+				}
+				let (identity, online) = self.resolve(&username);
+				if identity == self.identity(token) {
+					self.send(token, &ServerEvent::Error { reason: String::from("Hand the room over to someone else instead") });
+					return;
+				}
+				// Anyone could take over the username of someone without an account
+				let account = match identity {
+					Target::Name(account) if online.is_some() || self.accounts.is_registered(&account) => account,
+					_ => {
+						self.send(token, &ServerEvent::Error { reason: format!("{} has no account, only registered users hold roles", username) });
+						return;
+					}
+				};
+				let by = self.clients[&token].username.clone();
+				let username = online.map_or(username, |t| self.clients[&t].username.clone());
+				log!(Info, "{} made {} {} of {}", by, username, role, room);
+				self.moderation.set_role(&room, &account, role);
+				self.announce(token, &room, &ServerEvent::RoleChanged { room: room.clone(), username, role, by });
 			}
 		}
+	}
+}
+
+/// When a sanction of `seconds` ends; never if no duration was given.
+/// A duration too long to tell the end of is refused rather than made permanent.
+fn sanction_end(seconds: Option<u64>) -> Result<Option<DateTime<Utc>>, String> {
+	let seconds = match seconds {
+		Some(seconds) => seconds,
+		None => return Ok(None),
+	};
+	chrono::Duration::from_std(Duration::from_secs(seconds)).ok()
+		.and_then(|duration| Utc::now().checked_add_signed(duration))
+		.map(Some)
+		.ok_or_else(|| format!("A duration of {} seconds is too long, leave it out for a permanent sanction", seconds))
+}
+
+fn until_text(until: Option<DateTime<Utc>>) -> String {
+	match until {
+		Some(until) => format!(" until {}", until.format("%Y-%m-%d %H:%M UTC")),
+		None => String::new(),
+	}
+}
 
-		sleep(Duration::from_millis(100));
-	});
+/// Requests from outside the event loop.
+pub enum Control {
+	/// Tells clients why the server is going away and when it should be back, then stops.
+	Shutdown { reason: Option<String>, restart_in: Option<u64> },
+	/// A command from the admin console or control socket, answered on the channel.
+	Admin(Command, mpsc::Sender<Reply>),
+	/// Password work the worker finished for a client.
+	Password(Token, Outcome),
+	/// A search the search thread finished, with what it found.
+	Searched(Search, io::Result<Vec<Msg>>),
+	/// A scrape of the metrics endpoint, answered with the page.
+	Metrics(mpsc::Sender<String>),
 }
 
-pub fn start(port: &str) -> std::io::Result<()>{
-	let listener = TcpListener::bind("127.0.0.1:".to_string() + port)?;
-	listener.set_nonblocking(true)?;
+/// Sends `Control` requests to the event loop from other threads.
+#[derive(Clone)]
+pub struct Handle {
+	control: mpsc::Sender<Control>,
+	waker: Arc<Waker>,
+}
 
-	let roomlist = RoomList::default();
-	let userlist
+impl Handle {
+	pub fn send(&self, control: Control) {
+		if self.control.send(control).is_ok() {
+			self.waker.wake().ok();
+		}
+	}
 
-	let mut clients: HashMap<std::net::SocketAddr, Connection> = HashMap::new();
+	/// Runs an admin command on the event loop and waits for the reply,
+	/// which never comes once the loop has stopped.
+	pub fn ask(&self, command: Command) -> Option<Reply> {
+		let (reply_tx, reply) = mpsc::channel();
+		self.send(Control::Admin(command, reply_tx));
+		reply.recv().ok()
+	}
 
-	let (tx, rx) = mpsc::channel::<Msg>();
+	/// The metrics page as of now, unless the event loop has stopped.
+	pub fn metrics(&self) -> Option<String> {
+		let (page_tx, page) = mpsc::channel();
+		self.send(Control::Metrics(page_tx));
+		page.recv().ok()
+	}
+}
 
-	let shared_tx = Arc::new(Mutex::new(tx));
+pub fn start(settings: config::Server, history: HistoryConfig, auth: AuthConfig, moderation: PathBuf, tls: Option<Arc<ServerConfig>>, reload: Reload) -> std::io::Result<()>{
+	let addrs = bind_addrs(&settings)?;
+	if addrs.len() > MAX_LISTENERS {
+		return Err(io::Error::new(ErrorKind::InvalidInput, format!("Can't listen on more than {} addresses", MAX_LISTENERS)));
+	}
+
+	let mut poll = Poll::new()?;
+	let mut events = Events::with_capacity(1024);
+	let mut listeners = Vec::new();
+	for (i, addr) in addrs.iter().enumerate() {
+		// Leave the IPv4 side of a port to an IPv4 listener if there is one
+		let v6_only = addrs.iter().any(|other| other.is_ipv4() && other.port() == addr.port());
+		let mut listener = listen(*addr, v6_only)
+			.map_err(|err| io::Error::new(err.kind(), format!("Can't listen on {}: {}", addr, err)))?;
+		poll.registry().register(&mut listener, Token(i), Interest::READABLE)?;
+		println!("Listening on {}", listener.local_addr()?);
+		listeners.push(listener);
+	}
 
+	let (control_tx, control) = mpsc::channel();
+	let handle = Handle { control: control_tx, waker: Arc::new(Waker::new(poll.registry(), WAKER)?) };
+	let mut server = Server::new(History::new(history)?, Accounts::load(auth)?, Moderation::load(moderation)?, tls, settings, reload, handle.clone())?;
+
+	// Both closed again when the server stops, however it does
+	#[cfg(unix)]
+	let _control_socket = match &server.settings.control_socket {
+		Some(path) => Some(ControlSocket::open(path, handle.clone())?),
+		None => None,
+	};
+	#[cfg(not(unix))]
+	if server.settings.control_socket.is_some() {
+		return Err(io::Error::new(ErrorKind::Unsupported, "The control socket needs a Unix system"));
+	}
+	if let Some(addr) = server.settings.metrics {
+		metrics::listen(addr, handle.clone())?;
+	}
+	let _console = Console::open(handle.clone());
+	ctrlc::set_handler(move || {
+		handle.send(Control::Shutdown { reason: None, restart_in: None });
+	}).map_err(|err| io::Error::other(format!("Can't handle signals: {}", err)))?;
+
+	// Set once shutting down, to when the last clients get cut off
+	let mut deadline: Option<Instant> = None;
 	loop {
-		if let Ok((socket, addr)) = listener.accept(){
-			println!("Client connected! {}", addr);
+		let timeout = if !server.unread.is_empty() {
+			Some(Duration::ZERO)
+		} else {
+			deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+		};
+		if let Err(err) = poll.poll(&mut events, timeout) {
+			if err.kind() == ErrorKind::Interrupted {
+				continue;
+			}
+			return Err(err);
+		}
 
-			clients.insert(addr, Connection {
-				stream: socket.try_clone().expect("Failed to clone client"),
-    			username: String::new(),
-			});
-			handle_client(socket, addr, shared_tx.lock().unwrap().clone(), &mut roomlist);
+		for event in events.iter() {
+			match event.token() {
+				WAKER => {
+					while let Ok(command) = control.try_recv() {
+						match command {
+							// Asked again while waiting: stop waiting
+							Control::Shutdown { .. } if deadline.is_some() => deadline = Some(Instant::now()),
+							Control::Shutdown { reason, restart_in } => {
+								// Dropping the listeners stops accepting
+								listeners.clear();
+								server.shutdown(reason, restart_in);
+								deadline = Some(Instant::now() + Duration::from_secs(server.settings.shutdown_timeout));
+							}
+							Control::Admin(command, reply) => {
+								reply.send(server.admin(command).unwrap_or_else(|reason| Reply::Error { reason })).ok();
+							}
+							Control::Password(token, outcome) => server.password_done(token, outcome),
+							Control::Searched(search, results) => server.searched(search, results),
+							Control::Metrics(page) => {
+								page.send(server.metrics()).ok();
+							}
+						}
+					}
+				}
+				Token(i) if i < listeners.len() => server.accept(&listeners[i], poll.registry())?,
+				token => {
+					if event.is_readable() || event.is_read_closed() {
+						server.read(token);
+					}
+					if event.is_writable() {
+						server.flush(token);
+					}
+				}
+			}
+			server.reap();
+		}
+		server.read_unread();
+		server.reap();
+
+		if deadline.is_some_and(|deadline| server.clients.is_empty() || Instant::now() >= deadline) {
+			server.finish();
+			return Ok(());
 		}
+	}
+}
 
-		if let Ok(msg) = rx.try_recv() {
-			for (_k, con) in clients.iter_mut() {
-				let outbound_json = serde_json::to_string(&msg).unwrap();
-				let outbound = outbound_json.as_bytes();
-				con.stream.write_all(&size_of_val(outbound).to_be_bytes()).unwrap();
-				con.stream.write_all(outbound).map(|_| con).ok();
+/// Socket addresses of the `bind` entries, in order and without repeats.
+fn bind_addrs(settings: &config::Server) -> io::Result<Vec<SocketAddr>> {
+	let mut addrs: Vec<SocketAddr> = Vec::new();
+	for entry in &settings.bind {
+		let resolved: Vec<SocketAddr> = if let Ok(addr) = entry.parse::<SocketAddr>() {
+			vec![addr]
+		} else if let Ok(ip) = entry.parse::<IpAddr>() {
+			vec![SocketAddr::new(ip, settings.port)]
+		} else {
+			let (host, port) = match entry.rsplit_once(':') {
+				Some((host, port)) => (host, port.parse().map_err(|_| {
+					io::Error::new(ErrorKind::InvalidInput, format!("Invalid port in bind address {}", entry))
+				})?),
+				None => (entry.as_str(), settings.port),
+			};
+			(host, port).to_socket_addrs()
+				.map_err(|err| io::Error::new(err.kind(), format!("Can't resolve {}: {}", host, err)))?
+				.collect()
+		};
+		for addr in resolved {
+			if !addrs.contains(&addr) {
+				addrs.push(addr);
 			}
 		}
+	}
+	Ok(addrs)
+}
 
-		sleep(Duration::from_millis(100));
+/// Opens a non-blocking listener, with IPv6 ones also taking IPv4 connections unless `v6_only`.
+fn listen(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+	let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+	if addr.is_ipv6() {
+		socket.set_only_v6(v6_only)?;
 	}
+	socket.set_reuse_address(true)?;
+	socket.bind(&addr.into())?;
+	socket.listen(1024)?;
+	socket.set_nonblocking(true)?;
+	Ok(TcpListener::from_std(socket.into()))
 }
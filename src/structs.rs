@@ -1,32 +1,42 @@
-use std::{net::{TcpStream, SocketAddr}, collections::HashMap};
+use std::{net::SocketAddr, collections::HashMap};
 
 use chrono::{DateTime, Utc};
+use mio::{net::TcpStream, Token};
 use serde::{Serialize, Deserialize};
 use tui::style::Color;
 
-pub struct Connection {
-	pub(crate) stream: TcpStream,
-	pub(crate) username: String
-}
+use crate::frame::{FrameDecoder, FrameEncoder};
+use crate::protocol::Capability;
+use crate::ratelimit::RateLimiter;
+use crate::tls::Stream;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum MsgType {
-	ConnectionRequest,
-	Message,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MessageWrapper {
-	pub msg_type: MsgType,
-	pub msg: String
+pub struct Connection {
+	pub(crate) stream: Stream<TcpStream>,
+	pub(crate) addr: SocketAddr,
+	pub(crate) decoder: FrameDecoder,
+	pub(crate) encoder: FrameEncoder,
+	pub(crate) username: String,
+	pub(crate) welcomed: bool,
+	/// Capabilities negotiated in the handshake
+	pub(crate) capabilities: Vec<Capability>,
+	/// Lowercased name of the account the client logged in to or registered
+	pub(crate) account: Option<String>,
+	/// Key published in the handshake for end-to-end encrypted direct messages
+	pub(crate) public_key: Option<String>,
+	pub(crate) limiter: RateLimiter
 }
 
+/// A chat message as delivered by the server.
+///
+/// `sender`, `timestamp` and `seq` are always filled in server-side.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Msg {
 	pub content: String,
 	pub sender: String,
 	pub color: Color,
-	pub timestamp: DateTime<Utc>
+	pub timestamp: DateTime<Utc>,
+	pub room: String,
+	pub seq: u64
 }	
 
 impl Default for Msg {
@@ -35,37 +45,122 @@ impl Default for Msg {
 			content: String::new(),
 			sender: String::new(),
 			color: Color::White,
-			timestamp: Utc::now()
+			timestamp: Utc::now(),
+			room: String::new(),
+			seq: 0
 		}
 	}
 }
 
+/// Ciphertext only the two parties of a direct message can open, base64.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Sealed {
+	pub nonce: String,
+	pub ciphertext: String
+}
+
+/// A private message between two users, routed only to them.
+///
+/// The content is sealed by the sender's client; `sender_key` is the key the
+/// sender published, which the recipient needs to open it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DirectMsg {
+	pub sealed: Sealed,
+	pub sender: String,
+	pub sender_key: String,
+	pub recipient: String,
+	pub color: Color,
+	pub timestamp: DateTime<Utc>
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ConnectionRequest {
+	pub version: u32,
+	pub capabilities: Vec<Capability>,
 	pub username: String,
-	pub room: String
+	pub room: String,
+	/// Logs in to the account registered under `username`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub password: Option<String>,
+	/// Key other users encrypt direct messages to this client with, base64 x25519.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub public_key: Option<String>
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Room {
-	pub clients: Vec<SocketAddr>,
+	pub clients: Vec<Token>,
+	/// Sequence number of the last message sent to the room.
+	pub seq: u64,
 	//todo room options
 }
 
 impl Room {
-	pub fn add_user(&mut self, user: SocketAddr) {
-		self.clients.push(user);
+	/// Advances the room's sequence and returns the number for the next message.
+	pub fn next_seq(&mut self) -> u64 {
+		self.seq += 1;
+		self.seq
+	}
+
+	pub fn add_user(&mut self, user: Token) {
+		if !self.has_user(user) {
+			self.clients.push(user);
+		}
+	}
+
+	pub fn has_user(&self, user: Token) -> bool {
+		self.clients.contains(&user)
+	}
+
+	pub fn remove_user(&mut self, user: Token) {
+		self.clients.retain(|client| *client != user);
 	}
 }
 
+pub const DEFAULT_ROOM: &str = "_default";
+pub const MAX_ROOM_NAME: usize = 32;
+
 #[derive(Clone)]
-pub struct RoomList<'a> {
-	pub rooms: &'a HashMap<String, Room>
+pub struct RoomList {
+	pub rooms: HashMap<String, Room>
 }
 
-impl Default for RoomList<'a> {
+impl Default for RoomList {
 	fn default() -> Self {
-        Self { rooms: Default::default() }
-    }
+		let mut rooms = HashMap::new();
+		rooms.insert(DEFAULT_ROOM.to_string(), Room::default());
+		Self { rooms }
+	}
+}
+
+impl RoomList {
+	pub fn create(&mut self, name: &str) -> Result<(), String> {
+		if name.is_empty() || name.len() > MAX_ROOM_NAME {
+			return Err(format!("Room names must be 1 to {} characters long", MAX_ROOM_NAME));
+		}
+		// Names double as directory names for the history log
+		if !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '#') {
+			return Err(String::from("Room names may only contain letters, digits, '-', '_' and '#'"));
+		}
+		if self.rooms.contains_key(name) {
+			return Err(format!("Room {} already exists", name));
+		}
+		self.rooms.insert(name.to_string(), Room::default());
+		Ok(())
+	}
+
+	/// Names of all rooms, sorted.
+	pub fn names(&self) -> Vec<String> {
+		let mut names: Vec<String> = self.rooms.keys().cloned().collect();
+		names.sort();
+		names
+	}
+
+	/// Removes a client from every room it is in.
+	pub fn remove_user(&mut self, user: Token) {
+		for room in self.rooms.values_mut() {
+			room.remove_user(user);
+		}
+	}
 }
 
@@ -0,0 +1,233 @@
+use std::{convert::TryFrom, io::{self, Read, Write}, path::Path, sync::Arc};
+
+use rustls::{
+	client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+	crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+	pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+	CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+	ServerConfig, ServerConnection, SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+/// A connection's byte stream, either plain or wrapped in TLS.
+///
+/// TLS records are handled inside `Read` and `Write`, so the frame codecs
+/// work the same over both.
+pub enum Stream<S: Read + Write> {
+	Plain(S),
+	Client(Box<StreamOwned<ClientConnection, S>>),
+	Server(Box<StreamOwned<ServerConnection, S>>),
+}
+
+impl<S: Read + Write> Stream<S> {
+	/// The underlying socket.
+	pub fn get_ref(&self) -> &S {
+		match self {
+			Stream::Plain(sock) => sock,
+			Stream::Client(tls) => &tls.sock,
+			Stream::Server(tls) => &tls.sock,
+		}
+	}
+
+	/// Runs the TLS handshake to completion, on a blocking socket.
+	pub fn handshake(&mut self) -> io::Result<()> {
+		match self {
+			Stream::Plain(_) => (),
+			Stream::Client(tls) => while tls.conn.is_handshaking() {
+				tls.conn.complete_io(&mut tls.sock)?;
+			},
+			Stream::Server(tls) => while tls.conn.is_handshaking() {
+				tls.conn.complete_io(&mut tls.sock)?;
+			},
+		}
+		Ok(())
+	}
+
+	/// Fingerprint of the key the server presented, once the handshake is done.
+	pub fn peer_fingerprint(&self) -> Option<String> {
+		let certs = match self {
+			Stream::Plain(_) => return None,
+			Stream::Client(tls) => tls.conn.peer_certificates()?,
+			Stream::Server(tls) => tls.conn.peer_certificates()?,
+		};
+		fingerprint(certs.first()?).ok()
+	}
+
+	/// Tells a TLS peer the stream is ending on purpose. Best effort, as the
+	/// socket is usually shut down right after.
+	pub fn close_notify(&mut self) {
+		match self {
+			Stream::Plain(_) => (),
+			Stream::Client(tls) => {
+				tls.conn.send_close_notify();
+				tls.conn.write_tls(&mut tls.sock).ok();
+			}
+			Stream::Server(tls) => {
+				tls.conn.send_close_notify();
+				tls.conn.write_tls(&mut tls.sock).ok();
+			}
+		}
+	}
+}
+
+impl<S: Read + Write> Read for Stream<S> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Stream::Plain(sock) => sock.read(buf),
+			Stream::Client(tls) => tls.read(buf),
+			Stream::Server(tls) => tls.read(buf),
+		}
+	}
+}
+
+impl<S: Read + Write> Write for Stream<S> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self {
+			Stream::Plain(sock) => sock.write(buf),
+			Stream::Client(tls) => tls.write(buf),
+			Stream::Server(tls) => tls.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			Stream::Plain(sock) => sock.flush(),
+			Stream::Client(tls) => tls.flush(),
+			Stream::Server(tls) => tls.flush(),
+		}
+	}
+}
+
+/// How the client decides to trust a server's certificate.
+pub enum Trust {
+	/// Certificates signed by the CA in this PEM file.
+	Ca(String),
+	/// Whatever certificate carries the public key with this fingerprint.
+	Fingerprint(String),
+	/// Any certificate; the caller checks its key against `known_hosts` once
+	/// the handshake is done and before sending anything.
+	FirstUse,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+	Arc::new(ring::default_provider())
+}
+
+fn invalid(what: String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidInput, what)
+}
+
+/// SHA-256 of a certificate's public key as colon separated hex, so it stays
+/// the same when a certificate is renewed with the same key.
+pub fn fingerprint(cert: &CertificateDer) -> Result<String, rustls::Error> {
+	let cert = webpki::EndEntityCert::try_from(cert)
+		.map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+	let digest = Sha256::digest(cert.subject_public_key_info().as_ref());
+	Ok(digest.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(":"))
+}
+
+/// Compares fingerprints ignoring case and colons.
+pub fn same_fingerprint(a: &str, b: &str) -> bool {
+	let normalize = |fp: &str| fp.replace(':', "").to_lowercase();
+	normalize(a) == normalize(b)
+}
+
+/// Loads the server's certificate chain and private key, returning the TLS
+/// config and the fingerprint of the certificate's key.
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<(Arc<ServerConfig>, String)> {
+	let certs = CertificateDer::pem_file_iter(cert)
+		.and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+		.map_err(|err| invalid(format!("Failed to read certificate {}: {}", cert.display(), err)))?;
+	let first = certs.first().ok_or_else(|| invalid(format!("No certificate in {}", cert.display())))?;
+	let fingerprint = fingerprint(first).map_err(|err| invalid(format!("Bad certificate {}: {}", cert.display(), err)))?;
+	let key = PrivateKeyDer::from_pem_file(key)
+		.map_err(|err| invalid(format!("Failed to read private key {}: {}", key.display(), err)))?;
+
+	let config = ServerConfig::builder_with_provider(provider())
+		.with_safe_default_protocol_versions()
+		.and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+		.map_err(|err| invalid(format!("Invalid TLS certificate or key: {}", err)))?;
+	Ok((Arc::new(config), fingerprint))
+}
+
+pub fn client_config(trust: &Trust) -> io::Result<Arc<ClientConfig>> {
+	let builder = ClientConfig::builder_with_provider(provider())
+		.with_safe_default_protocol_versions()
+		.map_err(|err| invalid(err.to_string()))?;
+	let config = match trust {
+		Trust::Ca(path) => {
+			let mut roots = RootCertStore::empty();
+			for cert in CertificateDer::pem_file_iter(path).map_err(|err| invalid(format!("Failed to read CA {}: {}", path, err)))? {
+				let cert = cert.map_err(|err| invalid(format!("Failed to read CA {}: {}", path, err)))?;
+				roots.add(cert).map_err(|err| invalid(format!("Bad CA certificate in {}: {}", path, err)))?;
+			}
+			builder.with_root_certificates(roots).with_no_client_auth()
+		}
+		Trust::Fingerprint(fingerprint) => builder
+			.dangerous()
+			.with_custom_certificate_verifier(Arc::new(KeyVerifier { pinned: Some(fingerprint.clone()), provider: provider() }))
+			.with_no_client_auth(),
+		Trust::FirstUse => builder
+			.dangerous()
+			.with_custom_certificate_verifier(Arc::new(KeyVerifier { pinned: None, provider: provider() }))
+			.with_no_client_auth(),
+	};
+	Ok(Arc::new(config))
+}
+
+/// Wraps a connected socket in a TLS client session for `host`.
+pub fn connect<S: Read + Write>(config: Arc<ClientConfig>, host: &str, sock: S) -> io::Result<Stream<S>> {
+	let name = ServerName::try_from(host.to_string()).map_err(|err| invalid(format!("Invalid server name {}: {}", host, err)))?;
+	let conn = ClientConnection::new(config, name).map_err(io::Error::other)?;
+	Ok(Stream::Client(Box::new(StreamOwned::new(conn, sock))))
+}
+
+/// Accepts a server certificate by its key fingerprint alone, for servers
+/// without a CA, or any certificate when nothing is pinned. Handshake
+/// signatures are still checked against the certificate's key.
+#[derive(Debug)]
+struct KeyVerifier {
+	pinned: Option<String>,
+	provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for KeyVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp_response: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		let fingerprint = fingerprint(end_entity)?;
+		match &self.pinned {
+			Some(pinned) if !same_fingerprint(&fingerprint, pinned) => {
+				Err(rustls::Error::General(format!("server key fingerprint {} does not match the pinned one", fingerprint)))
+			}
+			_ => Ok(ServerCertVerified::assertion()),
+		}
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.provider.signature_verification_algorithms.supported_schemes()
+	}
+}
@@ -0,0 +1,134 @@
+//! Helpers shared by the integration tests, which drive a real `svchat -s` process.
+
+// Each test crate uses a different subset of these
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A server process with its own scratch directory, killed and cleaned up on drop.
+pub struct TestServer {
+    pub port: u16,
+    pub dir: PathBuf,
+    child: Child,
+    output: Receiver<String>,
+}
+
+impl TestServer {
+    /// Starts a server on a free port with `args` added, once `setup` has
+    /// filled its scratch directory.
+    pub fn start<F: FnOnce(&PathBuf) -> Vec<String>>(name: &str, setup: F) -> TestServer {
+        let port = free_port();
+        let dir = std::env::temp_dir().join(format!("svchat-test-{}-{}", name, port));
+        fs::create_dir_all(&dir).unwrap();
+        let args = setup(&dir);
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_svchat"))
+            .args(["-s", "-p", &port.to_string()])
+            .arg("--history-dir")
+            .arg(dir.join("history"))
+            .arg("--user-db")
+            .arg(dir.join("users.json"))
+            .arg("--moderation-db")
+            .arg(dir.join("moderation.json"))
+            .args(&args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start server");
+
+        // Drained on a thread so a chatty server never blocks on a full pipe
+        let stdout = child.stdout.take().unwrap();
+        let (tx, output) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let server = TestServer { port, dir, child, output };
+        server.wait_for_port(port);
+        server
+    }
+
+    pub fn wait_for_port(&self, port: u16) {
+        let deadline = Instant::now() + TIMEOUT;
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(Instant::now() < deadline, "server did not come up on port {}", port);
+            sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// The first line the server printed that contains `needle`.
+    pub fn output_line(&self, needle: &str) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(left) {
+                Ok(line) if line.contains(needle) => return line,
+                Ok(_) => (),
+                Err(_) => panic!("server never printed {:?}", needle),
+            }
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+pub fn send<S: Write>(stream: &mut S, value: &Value) {
+    let payload = serde_json::to_vec(value).unwrap();
+    stream.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+    stream.write_all(&payload).unwrap();
+    stream.flush().unwrap();
+}
+
+pub fn recv<S: Read>(stream: &mut S) -> Value {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).unwrap();
+    let mut payload = vec![0; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+pub fn hello(username: &str) -> Value {
+    json!({
+        "type": "hello",
+        "version": 1,
+        "capabilities": [],
+        "username": username,
+        "room": "_default",
+    })
+}
+
+/// Receives until an event of type `kind` arrives, skipping everything else.
+pub fn recv_type<S: Read>(stream: &mut S, kind: &str) -> Value {
+    loop {
+        let event = recv(stream);
+        if event["type"] == kind {
+            return event;
+        }
+    }
+}
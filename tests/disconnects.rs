@@ -0,0 +1,29 @@
+//! Clients that vanish while the server is still answering them.
+
+mod common;
+
+use std::{net::TcpStream, thread::sleep, time::Duration};
+
+use socket2::SockRef;
+
+use common::{hello, recv_type, send, TestServer, TIMEOUT};
+
+#[test]
+fn reset_right_after_hello_leaves_the_server_running() {
+    let server = TestServer::start("disconnects-reset", |_| Vec::new());
+
+    for _ in 0..20 {
+        let mut mallory = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+        send(&mut mallory, &hello("mallory"));
+        // Closing with a zero linger sends a reset, failing the server's replies
+        SockRef::from(&mallory).set_linger(Some(Duration::ZERO)).unwrap();
+        drop(mallory);
+    }
+    sleep(Duration::from_millis(100));
+
+    let mut alice = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    alice.set_read_timeout(Some(TIMEOUT)).unwrap();
+    send(&mut alice, &hello("alice"));
+    recv_type(&mut alice, "welcome");
+    recv_type(&mut alice, "members");
+}
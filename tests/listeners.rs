@@ -0,0 +1,74 @@
+//! The server listening on several addresses, IPv4 and IPv6, at once.
+
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+
+use serde_json::json;
+
+use common::{free_port, hello, recv, recv_type, send, TestServer, TIMEOUT};
+
+/// Whether this machine can use IPv6 loopback at all; CI containers often can't.
+fn has_ipv6() -> bool {
+    TcpListener::bind("[::1]:0").is_ok()
+}
+
+fn connect(ip: IpAddr, port: u16) -> TcpStream {
+    let sock = TcpStream::connect(SocketAddr::new(ip, port)).unwrap();
+    sock.set_read_timeout(Some(TIMEOUT)).unwrap();
+    sock
+}
+
+#[test]
+fn serves_ipv4_and_ipv6_loopback_together() {
+    if !has_ipv6() {
+        eprintln!("skipping, no IPv6 loopback");
+        return;
+    }
+    let server = TestServer::start("listeners-both", |_| {
+        vec![String::from("-a"), String::from("127.0.0.1"), String::from("-a"), String::from("::1")]
+    });
+    server.output_line(&format!("Listening on [::1]:{}", server.port));
+
+    let mut alice = connect(IpAddr::V4(Ipv4Addr::LOCALHOST), server.port);
+    send(&mut alice, &hello("alice"));
+    assert_eq!(recv(&mut alice)["type"], "welcome");
+    let mut bob = connect(IpAddr::V6(Ipv6Addr::LOCALHOST), server.port);
+    send(&mut bob, &hello("bob"));
+    assert_eq!(recv(&mut bob)["type"], "welcome");
+
+    // Clients of different listeners share the same rooms
+    send(&mut bob, &json!({ "type": "message", "room": "_default", "content": "over v6", "color": "White" }));
+    let msg = recv_type(&mut alice, "message");
+    assert_eq!(msg["sender"], "bob");
+    assert_eq!(msg["content"], "over v6");
+}
+
+#[test]
+fn dual_stack_listener_takes_ipv4_clients() {
+    if !has_ipv6() {
+        eprintln!("skipping, no IPv6 loopback");
+        return;
+    }
+    let server = TestServer::start("listeners-dual", |_| vec![String::from("-a"), String::from("::")]);
+
+    let mut alice = connect(IpAddr::V4(Ipv4Addr::LOCALHOST), server.port);
+    send(&mut alice, &hello("alice"));
+    assert_eq!(recv(&mut alice)["type"], "welcome");
+    // Logged as plain IPv4, not as an IPv4-mapped IPv6 address; matching alice's
+    // own address skips the connection the port probe made
+    server.output_line(&format!("Client connected! {}", alice.local_addr().unwrap()));
+}
+
+#[test]
+fn bind_entries_can_name_their_own_port() {
+    let other = free_port();
+    let server = TestServer::start("listeners-ports", |_| {
+        vec![String::from("-a"), String::from("127.0.0.1"), String::from("-a"), format!("127.0.0.1:{}", other)]
+    });
+    server.wait_for_port(other);
+
+    let mut alice = connect(IpAddr::V4(Ipv4Addr::LOCALHOST), other);
+    send(&mut alice, &hello("alice"));
+    assert_eq!(recv(&mut alice)["type"], "welcome");
+}
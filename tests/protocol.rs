@@ -0,0 +1,53 @@
+//! Clients breaking the protocol's limits.
+
+mod common;
+
+use std::{io::{Read, Write}, net::TcpStream};
+
+use serde_json::json;
+
+use common::{hello, recv_type, send, TestServer, TIMEOUT};
+
+/// `protocol::MAX_CONTENT_SIZE`; a binary crate has nothing for tests to import.
+const MAX_CONTENT_SIZE: usize = 4096;
+
+fn connect(server: &TestServer, username: &str) -> TcpStream {
+    let mut sock = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    sock.set_read_timeout(Some(TIMEOUT)).unwrap();
+    send(&mut sock, &hello(username));
+    recv_type(&mut sock, "welcome");
+    sock
+}
+
+#[test]
+fn hostile_length_prefix_closes_the_connection() {
+    let server = TestServer::start("protocol-prefix", |_| Vec::new());
+    let mut mallory = connect(&server, "mallory");
+
+    mallory.write_all(b"\xff\xff\xff\xff").unwrap();
+    let error = recv_type(&mut mallory, "protocol_error");
+    assert!(error["reason"].as_str().unwrap().contains("exceeds"), "{}", error);
+
+    // Nothing else comes, the server hangs up
+    let mut rest = Vec::new();
+    mallory.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn oversize_content_is_rejected() {
+    let server = TestServer::start("protocol-content", |_| Vec::new());
+    let mut alice = connect(&server, "alice");
+    let mut bob = connect(&server, "bob");
+
+    let content = "a".repeat(MAX_CONTENT_SIZE + 1);
+    send(&mut alice, &json!({ "type": "message", "room": "_default", "content": content, "color": "White" }));
+    let error = recv_type(&mut alice, "error");
+    assert!(error["reason"].as_str().unwrap().starts_with("Messages are limited to"), "{}", error);
+
+    // The connection survives and bob only ever sees what fits
+    send(&mut alice, &json!({ "type": "message", "room": "_default", "content": "short", "color": "White" }));
+    let msg = recv_type(&mut bob, "message");
+    assert_eq!(msg["content"], "short");
+    assert_eq!(recv_type(&mut alice, "message")["content"], "short");
+}
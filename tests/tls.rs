@@ -0,0 +1,101 @@
+//! The server speaking TLS with a certificate generated for each run.
+
+mod common;
+
+use std::{
+    convert::TryFrom,
+    fs,
+    io::Read,
+    net::TcpStream,
+    sync::Arc,
+};
+
+use rcgen::CertifiedKey;
+use rustls::{
+    pki_types::{CertificateDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use common::{hello, recv, recv_type, send, TestServer, TIMEOUT};
+
+fn tls_server(name: &str) -> (TestServer, CertifiedKey) {
+    let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost"), String::from("127.0.0.1")]).unwrap();
+    let server = TestServer::start(name, |dir| {
+        fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+        fs::write(dir.join("key.pem"), certified.key_pair.serialize_pem()).unwrap();
+        vec![
+            String::from("--tls-cert"),
+            dir.join("cert.pem").to_string_lossy().into_owned(),
+            String::from("--tls-key"),
+            dir.join("key.pem").to_string_lossy().into_owned(),
+        ]
+    });
+    (server, certified)
+}
+
+/// Connects trusting only the test certificate.
+fn connect(port: u16, cert: CertificateDer<'static>, host: &str) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(host.to_string()).unwrap();
+    let conn = ClientConnection::new(Arc::new(config), name).unwrap();
+
+    let sock = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    sock.set_read_timeout(Some(TIMEOUT)).unwrap();
+    StreamOwned::new(conn, sock)
+}
+
+#[test]
+fn framing_works_over_tls() {
+    let (server, certified) = tls_server("tls-framing");
+    let mut alice = connect(server.port, certified.cert.der().clone(), "localhost");
+    send(&mut alice, &hello("alice"));
+    assert_eq!(recv(&mut alice)["type"], "welcome");
+
+    // Together big enough that the backlog spans several TLS records
+    let content = "x".repeat(4000);
+    for _ in 0..10 {
+        send(&mut alice, &json!({ "type": "message", "room": "_default", "content": content, "color": "White" }));
+        recv_type(&mut alice, "message");
+    }
+
+    let mut bob = connect(server.port, certified.cert.der().clone(), "127.0.0.1");
+    let mut bob_hello = hello("bob");
+    bob_hello["capabilities"] = json!(["history"]);
+    send(&mut bob, &bob_hello);
+    assert_eq!(recv(&mut bob)["type"], "welcome");
+    let backlog = recv_type(&mut bob, "backlog");
+    let messages = backlog["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 10);
+    assert!(messages.iter().all(|m| m["sender"] == "alice" && m["content"] == content));
+}
+
+#[test]
+fn server_prints_key_fingerprint() {
+    let (server, certified) = tls_server("tls-fingerprint");
+    let line = server.output_line("key fingerprint");
+
+    let digest = Sha256::digest(certified.key_pair.public_key_der());
+    let expected: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    assert!(line.ends_with(&expected.join(":")), "unexpected fingerprint line: {}", line);
+}
+
+#[test]
+fn plaintext_client_is_not_welcomed() {
+    let (server, _) = tls_server("tls-plaintext");
+    let mut sock = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    sock.set_read_timeout(Some(TIMEOUT)).unwrap();
+    send(&mut sock, &hello("mallory"));
+
+    let mut reply = Vec::new();
+    sock.read_to_end(&mut reply).ok();
+    assert!(!String::from_utf8_lossy(&reply).contains("welcome"));
+}